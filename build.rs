@@ -0,0 +1,28 @@
+use std::{
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Build info for GET /version: the commit the binary was built from and when
+fn main() {
+    // "unknown" when building outside a git checkout, e.g. from a source archive
+    let commit = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|hash| hash.trim().to_string())
+        .filter(|hash| !hash.is_empty())
+        .unwrap_or_else(|| "unknown".to_string());
+    let built_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    println!("cargo:rustc-env=GIT_COMMIT_HASH={commit}");
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={built_at}");
+    // A new commit or changed sources rebuild the info, other changes keep the last build's
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=templates");
+}
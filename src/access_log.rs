@@ -0,0 +1,120 @@
+use std::{fmt::Write, time::Instant};
+
+use axum::{
+    body::{self, Body, HttpBody},
+    extract::{Request, State},
+    http::header,
+    middleware::Next,
+    response::Response,
+};
+use tracing::{info, warn};
+
+use crate::{client_ip::ClientIp, email_domain};
+
+// Form bodies larger than this aren't buffered for logging
+const MAX_LOGGED_BODY: usize = 16 * 1024;
+
+#[derive(Clone, Copy)]
+pub enum AccessLogFormat {
+    // Method, path, status and duration
+    Compact,
+    // Also the query, HTTP version, client IP, response size and user agent
+    Verbose,
+}
+
+#[derive(Clone, Copy)]
+pub struct AccessLogConfig {
+    pub format: AccessLogFormat,
+    // Also logs form submissions with email addresses redacted (ACCESS_LOG_BODIES=1),
+    // only honored in dev mode
+    pub bodies: bool,
+}
+
+// One line per request at info level, easier to scan than the request spans. Runs outside
+// the trace layer so the line doesn't carry the span's fields.
+pub async fn log(State(config): State<AccessLogConfig>, request: Request, next: Next) -> Response {
+    let start = Instant::now();
+    let method = request.method().clone();
+    let uri = request.uri().clone();
+    let version = request.version();
+    let client_ip = request.extensions().get::<ClientIp>().copied();
+    let user_agent = request
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("-")
+        .to_string();
+    let request = if config.bodies {
+        log_form_body(request).await
+    } else {
+        request
+    };
+
+    let response = next.run(request).await;
+    let status = response.status().as_u16();
+    let elapsed_ms = start.elapsed().as_secs_f64() * 1000.0;
+    match config.format {
+        AccessLogFormat::Compact => {
+            info!(target: "access", "{method} {} {status} {elapsed_ms:.1}ms", uri.path());
+        }
+        AccessLogFormat::Verbose => {
+            // Unknown for streamed bodies
+            let bytes = response
+                .body()
+                .size_hint()
+                .exact()
+                .map_or("-".to_string(), |bytes| bytes.to_string());
+            let client_ip = client_ip.unwrap_or(ClientIp(None));
+            info!(
+                target: "access",
+                "{method} {uri} {version:?} {status} {elapsed_ms:.1}ms client={client_ip} bytes={bytes} agent={user_agent:?}"
+            );
+        }
+    }
+    response
+}
+
+// Logs url-encoded form fields and hands the request on with the same body. Other bodies,
+// like multipart uploads, are only noted.
+async fn log_form_body(request: Request) -> Request {
+    let is_form = request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("application/x-www-form-urlencoded"));
+    let length = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|length| length.parse::<usize>().ok());
+    let (method, path) = (request.method().clone(), request.uri().path().to_string());
+    match (is_form, length) {
+        (_, None | Some(0)) => return request,
+        (true, Some(length)) if length <= MAX_LOGGED_BODY => {}
+        (_, Some(length)) => {
+            info!(target: "access", "{method} {path} body: {length} bytes, not logged");
+            return request;
+        }
+    }
+
+    let (parts, body) = request.into_parts();
+    let bytes = match body::to_bytes(body, MAX_LOGGED_BODY).await {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            // The body is gone, the handler gets an empty one and rejects the request
+            warn!(target: "access", "{method} {path} body could not be read: {err}");
+            return Request::from_parts(parts, Body::empty());
+        }
+    };
+    let fields: Vec<(String, String)> = serde_urlencoded::from_bytes(&bytes).unwrap_or_default();
+    let mut logged = String::new();
+    for (name, value) in &fields {
+        let value = match email_domain(value) {
+            Some(domain) => format!("***@{domain}"),
+            None => value.clone(),
+        };
+        let _ = write!(logged, " {name}={value:?}");
+    }
+    info!(target: "access", "{method} {path} body:{logged}");
+    Request::from_parts(parts, Body::from(bytes))
+}
@@ -0,0 +1,101 @@
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+// Recent changes to the contacts, oldest entries are dropped once the log is full
+pub struct ActivityLog {
+    capacity: usize,
+    events: Mutex<VecDeque<ActivityEvent>>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct ActivityEvent {
+    // Seconds since the Unix epoch
+    pub at: u64,
+    // The same moment as UTC date and time, for display
+    pub at_text: String,
+    pub kind: ActivityKind,
+    // None for events about several contacts, e.g. bulk actions and imports
+    pub contact_id: Option<usize>,
+    pub description: String,
+}
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ActivityKind {
+    Created,
+    Updated,
+    Deleted,
+    Archived,
+}
+
+impl ActivityLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            events: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn record(&self, kind: ActivityKind, contact_id: Option<usize>, description: String) {
+        if self.capacity == 0 {
+            return;
+        }
+        let at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+        let mut events = self.events.lock().unwrap();
+        if events.len() == self.capacity {
+            events.pop_front();
+        }
+        events.push_back(ActivityEvent {
+            at,
+            at_text: format_utc(at),
+            kind,
+            contact_id,
+            description,
+        });
+    }
+
+    // Newest first
+    pub fn recent(&self) -> Vec<ActivityEvent> {
+        self.events.lock().unwrap().iter().rev().cloned().collect()
+    }
+}
+
+// Formats as `YYYY-MM-DD HH:MM:SS UTC`, also the `utc` filter in templates
+pub fn format_utc(secs: u64) -> String {
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let time = secs % 86_400;
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02} UTC",
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+// Year, month and day of a day count since 1970-01-01, as in
+// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+pub fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
@@ -0,0 +1,26 @@
+use std::{
+    fs,
+    hash::{DefaultHasher, Hash, Hasher},
+};
+
+use tracing::{info, warn};
+
+// The stylesheet built by tailwind, see the README
+pub const STYLESHEET_PATH: &str = "assets/main.css";
+pub const STYLESHEET_URL: &str = "/assets/main.css";
+
+// The stylesheet's URL with a hash of its content, like /assets/main.3f2a9b1c0d4e5f67.css,
+// which cache_control::static_assets serves as immutable. Computed once at startup, so a
+// rebuilt stylesheet gets a new URL on the next start. None when the file can't be read,
+// the pages then link the plain URL. Leaked since it's routed like the other paths, which
+// are static.
+pub fn fingerprinted_stylesheet_url() -> Option<&'static str> {
+    let content = fs::read(STYLESHEET_PATH)
+        .inspect_err(|err| warn!("Not fingerprinting {STYLESHEET_PATH}: {err}"))
+        .ok()?;
+    let mut hasher = DefaultHasher::new();
+    content.hash(&mut hasher);
+    let url = format!("/assets/main.{:016x}.css", hasher.finish());
+    info!("Serving {STYLESHEET_PATH} as {url}");
+    Some(url.leak())
+}
@@ -0,0 +1,87 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{fs, task::JoinHandle, time};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::{persistence::write_atomically, snapshot::Snapshot, ContactsAppState, CounterAppState};
+
+const BACKUP_PREFIX: &str = "snapshot-";
+
+// Settings for the periodic snapshot backups, backups are off unless a directory is configured
+pub struct BackupConfig {
+    pub dir: PathBuf,
+    pub interval: Duration,
+    // None keeps every backup
+    pub retention: Option<Duration>,
+}
+
+// Writes a timestamped snapshot every interval and prunes the ones older than the retention
+pub fn spawn_backup_task(
+    config: BackupConfig,
+    counter: Arc<CounterAppState>,
+    contacts: Arc<ContactsAppState>,
+    shutdown: CancellationToken,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = time::interval(config.interval);
+        // The first tick completes immediately, so skip it to not back up an empty startup state
+        interval.tick().await;
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {},
+                _ = shutdown.cancelled() => break,
+            }
+            let snapshot = Snapshot::capture(&counter, &contacts).await;
+            match write_backup(&config.dir, &snapshot).await {
+                Ok(path) => info!("Wrote backup {}", path.display()),
+                Err(err) => warn!("Failed to write backup: {err}"),
+            }
+            // A retention reaching back past the epoch can't have anything to prune
+            let cutoff = config
+                .retention
+                .and_then(|retention| SystemTime::now().checked_sub(retention));
+            if let Some(cutoff) = cutoff {
+                if let Err(err) = prune_backups(&config.dir, cutoff).await {
+                    warn!("Failed to prune backups: {err}");
+                }
+            }
+        }
+    })
+}
+
+async fn write_backup(dir: &Path, snapshot: &Snapshot) -> std::io::Result<PathBuf> {
+    fs::create_dir_all(dir).await?;
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let path = dir.join(format!("{BACKUP_PREFIX}{timestamp}.json"));
+    let json = serde_json::to_vec_pretty(snapshot)?;
+    // A crash mid-write would otherwise leave a truncated backup that looks like a valid one
+    write_atomically(&path, &json).await?;
+    Ok(path)
+}
+
+// Removes backups last modified before the cutoff
+async fn prune_backups(dir: &Path, cutoff: SystemTime) -> std::io::Result<()> {
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let is_backup = entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| name.starts_with(BACKUP_PREFIX) && name.ends_with(".json"));
+        if !is_backup {
+            continue;
+        }
+        if entry.metadata().await?.modified()? < cutoff {
+            fs::remove_file(entry.path()).await?;
+            info!("Pruned backup {}", entry.path().display());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,181 @@
+use std::{
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{activity::civil_from_days, Contact};
+
+// Longest content line in octets before it gets folded (RFC 5545, section 3.1)
+const MAX_LINE_OCTETS: usize = 75;
+// Starts the events of birthdays without a year, a leap year so February 29 exists
+const UNKNOWN_YEAR: i64 = 2000;
+
+// A contact's birthday, the year is optional like in vCard
+#[derive(Clone, Copy, PartialEq)]
+pub struct Birthday {
+    year: Option<i64>,
+    month: i64,
+    day: i64,
+}
+
+impl Birthday {
+    // Reads YYYY-MM-DD, or --MM-DD when the year isn't known
+    pub fn parse(raw: &str) -> Option<Self> {
+        let raw = raw.trim();
+        let (year, month_day) = match raw.strip_prefix("--") {
+            Some(month_day) => (None, month_day),
+            None => {
+                let (year, month_day) = raw.split_once('-')?;
+                if year.len() != 4 {
+                    return None;
+                }
+                (Some(year.parse().ok()?), month_day)
+            }
+        };
+        let (month, day) = month_day.split_once('-')?;
+        if month.len() != 2 || day.len() != 2 {
+            return None;
+        }
+        let (month, day): (i64, i64) = (month.parse().ok()?, day.parse().ok()?);
+        // Without a year February 29 is allowed, it could be a leap year
+        let days_in_month = match month {
+            1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+            4 | 6 | 9 | 11 => 30,
+            2 if year.is_none_or(is_leap_year) => 29,
+            2 => 28,
+            _ => return None,
+        };
+        (1..=days_in_month)
+            .contains(&day)
+            .then_some(Self { year, month, day })
+    }
+
+    fn is_leap_day(&self) -> bool {
+        self.month == 2 && self.day == 29
+    }
+}
+
+// The form `parse` reads
+impl fmt::Display for Birthday {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.year {
+            Some(year) => write!(f, "{year:04}-{:02}-{:02}", self.month, self.day),
+            None => write!(f, "--{:02}-{:02}", self.month, self.day),
+        }
+    }
+}
+
+fn is_leap_year(year: i64) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+// An iCalendar feed with an all-day event every year on each contact's birthday. Archived
+// contacts and contacts without a birthday are left out.
+pub fn calendar(contacts: &[Contact]) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default();
+    let (year, month, day) = civil_from_days((now / 86_400) as i64);
+    let time = now % 86_400;
+    let stamp = format!(
+        "{year:04}{month:02}{day:02}T{:02}{:02}{:02}Z",
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    );
+
+    let mut feed = String::new();
+    for line in [
+        "BEGIN:VCALENDAR",
+        "VERSION:2.0",
+        "PRODID:-//axum-app//Contact birthdays//EN",
+        "CALSCALE:GREGORIAN",
+        "X-WR-CALNAME:Birthdays",
+    ] {
+        push_line(&mut feed, line);
+    }
+    for contact in contacts.iter().filter(|contact| !contact.archived) {
+        let Some(birthday) = Birthday::parse(&contact.birthday) else {
+            continue;
+        };
+        let start = format!(
+            "{:04}{:02}{:02}",
+            birthday.year.unwrap_or(UNKNOWN_YEAR),
+            birthday.month,
+            birthday.day
+        );
+        // Falls on February 28 in common years, the last day of February every year
+        let rule = if birthday.is_leap_day() {
+            "FREQ=YEARLY;BYMONTH=2;BYMONTHDAY=-1"
+        } else {
+            "FREQ=YEARLY"
+        };
+        push_line(&mut feed, "BEGIN:VEVENT");
+        push_line(&mut feed, &format!("UID:birthday-{}@axum-app", contact.id));
+        push_line(&mut feed, &format!("DTSTAMP:{stamp}"));
+        push_line(&mut feed, &format!("DTSTART;VALUE=DATE:{start}"));
+        push_line(&mut feed, &format!("RRULE:{rule}"));
+        push_line(
+            &mut feed,
+            &format!(
+                "SUMMARY:{}",
+                escape_text(&format!("{}'s birthday", contact.name))
+            ),
+        );
+        push_line(&mut feed, "TRANSP:TRANSPARENT");
+        push_line(&mut feed, "END:VEVENT");
+    }
+    push_line(&mut feed, "END:VCALENDAR");
+    feed
+}
+
+// Backslashes, semicolons, commas and line breaks are escaped in TEXT values, in vCards too
+pub fn escape_text(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' | ';' | ',' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\n' => escaped.push_str("\\n"),
+            '\r' => {}
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Reverses escape_text, a backslash before any other character is dropped
+pub fn unescape_text(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n' | 'N') => unescaped.push('\n'),
+            Some(escaped) => unescaped.push(escaped),
+            None => {}
+        }
+    }
+    unescaped
+}
+
+// Ends the line with CRLF and folds it after 75 octets, without splitting a character.
+// Every continuation line starts with a space. vCards fold the same way.
+pub fn push_line(feed: &mut String, line: &str) {
+    let mut octets = 0;
+    for c in line.chars() {
+        if octets + c.len_utf8() > MAX_LINE_OCTETS {
+            feed.push_str("\r\n ");
+            octets = 1;
+        }
+        feed.push(c);
+        octets += c.len_utf8();
+    }
+    feed.push_str("\r\n");
+}
@@ -0,0 +1,47 @@
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Uri};
+
+const HX_RETARGET: HeaderName = HeaderName::from_static("hx-retarget");
+const HX_RESWAP: HeaderName = HeaderName::from_static("hx-reswap");
+const HX_PUSH_URL: HeaderName = HeaderName::from_static("hx-push-url");
+
+// How a page is sent. Links and forms in the body are boosted, htmx fetches their pages
+// itself and only needs the content of <main>. Direct loads and history restores, which
+// htmx doesn't mark as boosted, get the whole document.
+#[derive(Clone, Copy, PartialEq)]
+pub enum PageMode {
+    Full,
+    Boosted,
+}
+
+impl PageMode {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        if headers.contains_key("hx-boosted") && !headers.contains_key("hx-history-restore-request")
+        {
+            Self::Boosted
+        } else {
+            Self::Full
+        }
+    }
+
+    // The block to render, the page's own block or the `body` block every page fills in
+    pub fn block(self, page_block: &str) -> &str {
+        match self {
+            Self::Full => page_block,
+            Self::Boosted => "body",
+        }
+    }
+
+    // For a boosted page, swaps it into <main> instead of the whole body and pushes its URL
+    // so the back button returns to the page before
+    pub fn headers(self, uri: &Uri) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if self == Self::Boosted {
+            headers.insert(HX_RETARGET, HeaderValue::from_static("#main"));
+            headers.insert(HX_RESWAP, HeaderValue::from_static("innerHTML"));
+            if let Ok(url) = HeaderValue::from_str(&uri.to_string()) {
+                headers.insert(HX_PUSH_URL, url);
+            }
+        }
+        headers
+    }
+}
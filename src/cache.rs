@@ -0,0 +1,147 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering::Relaxed},
+        Mutex,
+    },
+};
+
+use serde::Serialize;
+use tracing::debug;
+
+// Size-bounded LRU cache for rendered template blocks
+//
+// Entries belong to a scope (e.g. "contacts") so handlers that mutate the
+// underlying data can drop everything rendered from it with `invalidate`
+pub struct FragmentCache {
+    capacity: usize,
+    inner: Mutex<CacheInner>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    scope: String,
+    tpl_name: String,
+    tpl_blk: String,
+    ctx_key: String,
+}
+
+struct CacheEntry {
+    html: String,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct CacheInner {
+    entries: HashMap<CacheKey, CacheEntry>,
+    // Keys by recency stamp, so the least recently used entry is always the first
+    recency: BTreeMap<u64, CacheKey>,
+    // Monotonic counter used as the recency stamp of an entry
+    tick: u64,
+}
+
+#[derive(Serialize)]
+pub struct CacheStats {
+    hits: u64,
+    misses: u64,
+    entries: usize,
+    capacity: usize,
+}
+
+impl FragmentCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Mutex::new(CacheInner::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    // Returns the cached block or renders and stores it on a miss
+    pub fn get_or_render<E>(
+        &self,
+        scope: &str,
+        tpl_name: &str,
+        tpl_blk: &str,
+        ctx_key: &str,
+        render: impl FnOnce() -> Result<String, E>,
+    ) -> Result<String, E> {
+        let key = CacheKey {
+            scope: scope.into(),
+            tpl_name: tpl_name.into(),
+            tpl_blk: tpl_blk.into(),
+            ctx_key: ctx_key.into(),
+        };
+
+        if let Some(html) = self.lookup(&key) {
+            let hits = self.hits.fetch_add(1, Relaxed) + 1;
+            debug!(scope, block = tpl_blk, hits, "Fragment cache hit");
+            return Ok(html);
+        }
+        let misses = self.misses.fetch_add(1, Relaxed) + 1;
+        debug!(scope, block = tpl_blk, misses, "Fragment cache miss");
+
+        // Rendering happens outside the lock so a slow render doesn't block other lookups
+        let html = render()?;
+        self.store(key, html.clone());
+        Ok(html)
+    }
+
+    // Drops every entry rendered from the given scope
+    pub fn invalidate(&self, scope: &str) {
+        let mut inner = self.inner.lock().expect("Fragment cache lock poisoned");
+        inner.entries.retain(|key, _| key.scope != scope);
+        inner.recency.retain(|_, key| key.scope != scope);
+        debug!(scope, "Invalidated fragment cache");
+    }
+
+    // Hits and misses since startup, like the error counts
+    pub fn stats(&self) -> CacheStats {
+        let entries = self
+            .inner
+            .lock()
+            .expect("Fragment cache lock poisoned")
+            .entries
+            .len();
+        CacheStats {
+            hits: self.hits.load(Relaxed),
+            misses: self.misses.load(Relaxed),
+            entries,
+            capacity: self.capacity,
+        }
+    }
+
+    fn lookup(&self, key: &CacheKey) -> Option<String> {
+        let mut inner = self.inner.lock().expect("Fragment cache lock poisoned");
+        inner.tick += 1;
+        let tick = inner.tick;
+        let entry = inner.entries.get_mut(key)?;
+        let previous = std::mem::replace(&mut entry.last_used, tick);
+        let html = entry.html.clone();
+        inner.recency.remove(&previous);
+        inner.recency.insert(tick, key.clone());
+        Some(html)
+    }
+
+    fn store(&self, key: CacheKey, html: String) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut inner = self.inner.lock().expect("Fragment cache lock poisoned");
+        if inner.entries.len() >= self.capacity && !inner.entries.contains_key(&key) {
+            // Evict the least recently used entry
+            if let Some((_, oldest)) = inner.recency.pop_first() {
+                inner.entries.remove(&oldest);
+            }
+        }
+        inner.tick += 1;
+        let last_used = inner.tick;
+        inner.recency.insert(last_used, key.clone());
+        if let Some(replaced) = inner.entries.insert(key, CacheEntry { html, last_used }) {
+            inner.recency.remove(&replaced.last_used);
+        }
+    }
+}
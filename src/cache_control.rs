@@ -0,0 +1,182 @@
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    time::Duration,
+};
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+
+// Fingerprinted assets never change under the same name, so they can be cached for good
+const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+
+// Lets browsers cache static files, only successful responses are marked cacheable.
+// ServeDir already handles Last-Modified and If-Modified-Since, this adds an ETag derived
+// from the size and modification time and answers a matching If-None-Match with 304.
+pub async fn static_assets(
+    State(max_age): State<Duration>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let fingerprinted = is_fingerprinted(request.uri().path());
+    // If-None-Match takes precedence, so ServeDir must not answer 304 on the date alone
+    let if_none_match = request.headers_mut().remove(header::IF_NONE_MATCH);
+    if if_none_match.is_some() {
+        request.headers_mut().remove(header::IF_MODIFIED_SINCE);
+    }
+    let mut response = next.run(request).await;
+    if response.status() == StatusCode::OK {
+        if let Some(etag) = file_etag(response.headers()) {
+            if if_none_match.is_some_and(|candidates| etag_matches(&candidates, &etag)) {
+                let mut not_modified = Response::new(Body::empty());
+                *not_modified.status_mut() = StatusCode::NOT_MODIFIED;
+                for name in [header::LAST_MODIFIED, header::CONTENT_TYPE] {
+                    if let Some(value) = response.headers().get(&name) {
+                        not_modified.headers_mut().insert(name, value.clone());
+                    }
+                }
+                response = not_modified;
+            }
+            response.headers_mut().insert(header::ETAG, etag);
+        }
+    }
+    let status = response.status();
+    if status.is_success() || status.as_u16() == 304 {
+        let value = if fingerprinted {
+            HeaderValue::from_static(IMMUTABLE)
+        } else {
+            HeaderValue::from_str(&format!("public, max-age={}", max_age.as_secs()))
+                .expect("Cache-Control is valid")
+        };
+        response.headers_mut().insert(header::CACHE_CONTROL, value);
+    }
+    response
+}
+
+// Matches names like `main.3f2a9b1c.css`, a hash of at least 8 hex digits before the extension
+fn is_fingerprinted(path: &str) -> bool {
+    let file_name = path.rsplit('/').next().unwrap_or_default();
+    let mut parts = file_name.rsplit('.');
+    parts.next();
+    parts
+        .next()
+        .is_some_and(|hash| hash.len() >= 8 && hash.chars().all(|c| c.is_ascii_hexdigit()))
+        && parts.next().is_some()
+}
+
+// Weak, since it identifies the file version rather than the exact bytes of a response
+fn file_etag(headers: &HeaderMap) -> Option<HeaderValue> {
+    let last_modified = headers.get(header::LAST_MODIFIED)?;
+    let length = headers.get(header::CONTENT_LENGTH)?;
+    let mut hasher = DefaultHasher::new();
+    last_modified.as_bytes().hash(&mut hasher);
+    length.as_bytes().hash(&mut hasher);
+    HeaderValue::from_str(&format!("W/\"{:016x}\"", hasher.finish())).ok()
+}
+
+// Weak comparison as If-None-Match asks for, `W/` prefixes are ignored on both sides
+fn etag_matches(candidates: &HeaderValue, etag: &HeaderValue) -> bool {
+    let strip = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let Ok(etag) = etag.to_str().map(strip) else {
+        return false;
+    };
+    candidates.to_str().is_ok_and(|candidates| {
+        candidates
+            .split(',')
+            .any(|candidate| candidate.trim() == "*" || strip(candidate) == etag)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use axum::{middleware, Router};
+    use tower::ServiceExt;
+    use tower_http::services::ServeFile;
+
+    use super::*;
+
+    // Every test writes its own file, so they can run in parallel
+    fn stylesheet(test: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{test}-{}.css", std::process::id()));
+        fs::write(&path, "body { margin: 0 }").unwrap();
+        path
+    }
+
+    fn app(path: &PathBuf) -> Router {
+        Router::new()
+            .route_service("/assets/main.css", ServeFile::new(path))
+            .layer(middleware::from_fn_with_state(
+                Duration::from_secs(60),
+                static_assets,
+            ))
+    }
+
+    async fn get(app: &Router, headers: &[(header::HeaderName, &HeaderValue)]) -> Response {
+        let mut request = Request::get("/assets/main.css");
+        for (name, value) in headers {
+            request = request.header(name, *value);
+        }
+        app.clone()
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn repeated_request_with_the_etag_is_not_modified() {
+        let path = stylesheet("etag");
+        let app = app(&path);
+
+        let first = get(&app, &[]).await;
+        assert_eq!(first.status(), StatusCode::OK);
+        assert_eq!(first.headers()[header::CACHE_CONTROL], "public, max-age=60");
+        let etag = first.headers()[header::ETAG].clone();
+        assert!(etag.to_str().unwrap().starts_with("W/\""));
+
+        let second = get(&app, &[(header::IF_NONE_MATCH, &etag)]).await;
+        assert_eq!(second.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(second.headers()[header::ETAG], etag);
+        assert_eq!(
+            second.headers()[header::CACHE_CONTROL],
+            "public, max-age=60"
+        );
+
+        let last_modified = first.headers()[header::LAST_MODIFIED].clone();
+        let third = get(&app, &[(header::IF_MODIFIED_SINCE, &last_modified)]).await;
+        assert_eq!(third.status(), StatusCode::NOT_MODIFIED);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn if_none_match_takes_precedence_over_the_date() {
+        let path = stylesheet("precedence");
+        let app = app(&path);
+
+        let first = get(&app, &[]).await;
+        let last_modified = first.headers()[header::LAST_MODIFIED].clone();
+        let stale = HeaderValue::from_static("W/\"0000000000000000\"");
+        let response = get(
+            &app,
+            &[
+                (header::IF_NONE_MATCH, &stale),
+                (header::IF_MODIFIED_SINCE, &last_modified),
+            ],
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn recognizes_fingerprinted_names() {
+        assert!(is_fingerprinted("/assets/main.3f2a9b1c0d4e5f67.css"));
+        assert!(!is_fingerprinted("/assets/main.css"));
+        assert!(!is_fingerprinted("/assets/3f2a9b1c0d4e5f67"));
+    }
+}
@@ -0,0 +1,310 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering::SeqCst},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde_json::json;
+use tokio::{sync::Notify, time};
+
+use crate::{triggers::Triggers, Contact, ContactsAppState};
+
+// Deletions kept for delta clients, one that synced before the oldest of them starts over
+const MAX_TOMBSTONES: usize = 1000;
+
+// Counts changes to the contact list so clients can tell whether what they show is current.
+// Long polls, ETags and the contacts-changed event all go by this one version.
+pub struct ContactChanges {
+    version: AtomicU64,
+    notify: Notify,
+    // The version at startup, changes from before it aren't known
+    started: u64,
+    journal: Mutex<Journal>,
+}
+
+// The version that last changed each contact, for clients that only fetch what changed
+#[derive(Default)]
+struct Journal {
+    // The version each contact last changed at
+    contacts: HashMap<usize, u64>,
+    // Removed contacts and the version they were removed at, oldest first
+    deleted: VecDeque<(usize, u64)>,
+    // Deletions up to this version were dropped to stay within MAX_TOMBSTONES
+    forgotten: u64,
+}
+
+// What changed after a version, see `ContactChanges::delta`
+pub struct Delta {
+    pub version: u64,
+    // The version is unknown, e.g. from before a restart, or deletions after it were
+    // forgotten. Every contact is listed and deletions can't be told, so the client should
+    // replace what it has.
+    pub full: bool,
+    // Created or changed contacts with the version they last changed at
+    pub changed: HashMap<usize, u64>,
+    pub deleted: Vec<usize>,
+}
+
+impl ContactChanges {
+    // Starts at the time in milliseconds, so versions keep growing across restarts without
+    // being saved, as long as there are fewer than a thousand changes a second
+    pub fn new(contacts: &[Contact]) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let started = u64::try_from(now).unwrap_or_default();
+        let changes = Self {
+            version: AtomicU64::new(started),
+            notify: Notify::new(),
+            started,
+            journal: Mutex::new(Journal::default()),
+        };
+        changes.record(contacts, None);
+        changes
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version.load(SeqCst)
+    }
+
+    // Moves to the next version, notes the contacts it created, changed or removed by id and
+    // wakes everyone waiting for a change. Called with the contacts still locked, so the
+    // version and the journal match the list. Ids of contacts that never existed are ignored.
+    pub fn bump(&self, contacts: &[Contact], changed: impl IntoIterator<Item = usize>) {
+        self.version.fetch_add(1, SeqCst);
+        self.record(contacts, Some(changed.into_iter().collect()));
+        self.notify.notify_waiters();
+    }
+
+    // Like bump, for when the whole list was replaced, e.g. by restoring a snapshot. Every
+    // contact counts as changed.
+    pub fn bump_all(&self, contacts: &[Contact]) {
+        self.version.fetch_add(1, SeqCst);
+        self.record(contacts, None);
+        self.notify.notify_waiters();
+    }
+
+    // Notes the changed contacts with the current version, and the ones no longer in the
+    // list as removed. Without ids every contact is noted.
+    fn record(&self, contacts: &[Contact], changed: Option<HashSet<usize>>) {
+        let version = self.version();
+        let mut journal = self.journal.lock().unwrap();
+        let ids = changed.unwrap_or_else(|| {
+            let known = journal.contacts.keys().copied();
+            known
+                .chain(contacts.iter().map(|contact| contact.id))
+                .collect()
+        });
+        let present: HashSet<usize> = contacts
+            .iter()
+            .map(|contact| contact.id)
+            .filter(|id| ids.contains(id))
+            .collect();
+        for id in ids {
+            if present.contains(&id) {
+                journal.contacts.insert(id, version);
+                // An id can come back, e.g. with an import
+                journal.deleted.retain(|(deleted, _)| *deleted != id);
+            } else if journal.contacts.remove(&id).is_some() {
+                journal.deleted.push_back((id, version));
+            }
+        }
+        while journal.deleted.len() > MAX_TOMBSTONES {
+            if let Some((_, deleted_at)) = journal.deleted.pop_front() {
+                journal.forgotten = deleted_at;
+            }
+        }
+    }
+
+    // The contacts changed and removed after the `since` version. Like `bump` it goes with
+    // the contacts locked, so the changed ones can be looked up in the same state.
+    pub fn delta(&self, since: Option<u64>) -> Delta {
+        let version = self.version();
+        let journal = self.journal.lock().unwrap();
+        let since = since.filter(|since| {
+            (self.started..=version).contains(since) && *since >= journal.forgotten
+        });
+        let changed = journal
+            .contacts
+            .iter()
+            .filter(|(_, changed_at)| since.is_none_or(|since| **changed_at > since))
+            .map(|(id, changed_at)| (*id, *changed_at))
+            .collect();
+        let deleted = match since {
+            Some(since) => journal
+                .deleted
+                .iter()
+                .filter(|(_, deleted_at)| *deleted_at > since)
+                .map(|(id, _)| *id)
+                .collect(),
+            None => Vec::new(),
+        };
+        Delta {
+            version,
+            full: since.is_none(),
+            changed,
+            deleted,
+        }
+    }
+
+    // Covers everything rendered from the contacts, `variant` tells apart the pages
+    pub fn etag(&self, variant: &str) -> String {
+        let mut hasher = DefaultHasher::new();
+        variant.hash(&mut hasher);
+        format!("\"{:x}-{:016x}\"", self.version(), hasher.finish())
+    }
+
+    // Returns the current version once it differs from `since`, or after `timeout` without
+    // a change. Differs rather than is past, since a version from before a restart could be
+    // ahead if the clock was turned back.
+    pub async fn wait_for_change(&self, since: u64, timeout: Duration) -> u64 {
+        let deadline = time::Instant::now() + timeout;
+        loop {
+            // Registered before checking, so a bump in between still wakes it
+            let notified = self.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            let version = self.version();
+            if version != since {
+                return version;
+            }
+            if time::timeout_at(deadline, notified).await.is_err() {
+                return self.version();
+            }
+        }
+    }
+}
+
+// Tells htmx about requests that changed the contacts with a contacts-changed event carrying
+// the new version, so pages can tell whether what they show is still current
+pub async fn announce_version(
+    State(state): State<Arc<ContactsAppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let before = state.changes.version();
+    let response = next.run(request).await;
+    let version = state.changes.version();
+    if version == before {
+        return response;
+    }
+    let triggers = Triggers::new().trigger("contacts-changed", json!({ "version": version }));
+    (triggers, response).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delta_lists_changed_and_deleted_contacts() {
+        let mut contacts = vec![
+            Contact::new("A", "a@example.com"),
+            Contact::new("B", "b@example.com"),
+        ];
+        let (a, b) = (contacts[0].id, contacts[1].id);
+        let changes = ContactChanges::new(&contacts);
+        let since = changes.version();
+
+        contacts[0].name = "Renamed".to_string();
+        changes.bump(&contacts, [a]);
+        let delta = changes.delta(Some(since));
+        assert!(!delta.full);
+        assert_eq!(delta.changed, HashMap::from([(a, changes.version())]));
+        assert!(delta.deleted.is_empty());
+
+        let renamed_at = changes.version();
+        contacts.remove(1);
+        changes.bump(&contacts, [b]);
+        let delta = changes.delta(Some(renamed_at));
+        assert!(delta.changed.is_empty());
+        assert_eq!(delta.deleted, vec![b]);
+
+        assert!(changes.delta(Some(changes.version())).changed.is_empty());
+    }
+
+    #[test]
+    fn unknown_versions_get_everything() {
+        let contacts = vec![Contact::new("A", "a@example.com")];
+        let changes = ContactChanges::new(&contacts);
+        for since in [None, Some(0), Some(changes.version() + 1)] {
+            let delta = changes.delta(since);
+            assert!(delta.full);
+            assert_eq!(delta.changed.len(), 1);
+        }
+    }
+
+    #[test]
+    fn ids_of_missing_contacts_are_ignored() {
+        let contacts = vec![Contact::new("A", "a@example.com")];
+        let changes = ContactChanges::new(&contacts);
+        let since = changes.version();
+        changes.bump(&contacts, [usize::MAX]);
+        let delta = changes.delta(Some(since));
+        assert!(delta.changed.is_empty());
+        assert!(delta.deleted.is_empty());
+    }
+
+    #[test]
+    fn returning_contacts_are_no_longer_deleted() {
+        let mut contacts = vec![Contact::new("A", "a@example.com")];
+        let id = contacts[0].id;
+        let changes = ContactChanges::new(&contacts);
+        let since = changes.version();
+        let removed = contacts.remove(0);
+        changes.bump(&contacts, [id]);
+        contacts.push(removed);
+        changes.bump(&contacts, [id]);
+
+        let delta = changes.delta(Some(since));
+        assert!(delta.deleted.is_empty());
+        assert_eq!(delta.changed, HashMap::from([(id, changes.version())]));
+    }
+
+    #[test]
+    fn forgotten_deletions_get_everything() {
+        let mut contacts: Vec<Contact> = (0..=MAX_TOMBSTONES)
+            .map(|_| Contact::new("A", "a@example.com"))
+            .collect();
+        let changes = ContactChanges::new(&contacts);
+        let since = changes.version();
+        let first = contacts.remove(0);
+        changes.bump(&contacts, [first.id]);
+        let kept_from = changes.version();
+        while let Some(contact) = contacts.pop() {
+            changes.bump(&contacts, [contact.id]);
+        }
+
+        assert!(changes.delta(Some(since)).full);
+        let delta = changes.delta(Some(kept_from));
+        assert!(!delta.full);
+        assert_eq!(delta.deleted.len(), MAX_TOMBSTONES);
+    }
+
+    #[test]
+    fn replacing_the_list_changes_every_contact() {
+        let mut contacts = vec![Contact::new("A", "a@example.com")];
+        let old = contacts[0].id;
+        let changes = ContactChanges::new(&contacts);
+        let since = changes.version();
+        contacts = vec![Contact::new("B", "b@example.com")];
+        changes.bump_all(&contacts);
+
+        let delta = changes.delta(Some(since));
+        assert_eq!(
+            delta.changed.keys().collect::<Vec<_>>(),
+            vec![&contacts[0].id]
+        );
+        assert_eq!(delta.deleted, vec![old]);
+    }
+}
@@ -0,0 +1,30 @@
+use std::{sync::Arc, time::Duration};
+
+use tokio::{task::JoinHandle, time};
+use tokio_util::sync::CancellationToken;
+use tracing::debug;
+
+use crate::ContactsAppState;
+
+// Periodically sweeps expired entries out of every TTL-based store
+pub fn spawn_cleanup_task(
+    interval: Duration,
+    contacts: Arc<ContactsAppState>,
+    shutdown: CancellationToken,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = time::interval(interval);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {},
+                _ = shutdown.cancelled() => break,
+            }
+            let tokens = contacts.verification_tokens.purge_expired().await;
+            let sessions = match &contacts.app_state.sessions {
+                Some(sessions) => sessions.purge_expired().await,
+                None => 0,
+            };
+            debug!(tokens, sessions, "Purged expired entries");
+        }
+    })
+}
@@ -0,0 +1,69 @@
+use std::{
+    convert::Infallible,
+    fmt,
+    net::{IpAddr, SocketAddr},
+};
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{request::Parts, HeaderMap, Request},
+};
+
+// The address a request came from, None where it can't be told, e.g. on a Unix socket
+// without a trusted proxy in front. Resolved once per request by `resolve`.
+#[derive(Clone, Copy)]
+pub struct ClientIp(pub Option<IpAddr>);
+
+impl fmt::Display for ClientIp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(ip) => ip.fmt(f),
+            None => f.write_str("unknown"),
+        }
+    }
+}
+
+// Stores the client IP on the request for handlers and the request span. The connecting
+// address is used unless `trust_proxy` is set, then the proxy's X-Forwarded-For entry is.
+pub fn resolve(trust_proxy: bool) -> impl Fn(Request<Body>) -> Request<Body> + Clone {
+    move |mut request| {
+        let peer = request
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+        let forwarded = trust_proxy
+            .then(|| forwarded_for(request.headers()))
+            .flatten();
+        request
+            .extensions_mut()
+            .insert(ClientIp(forwarded.or(peer)));
+        request
+    }
+}
+
+// The last entry is the one added by the proxy in front of the app, anything before it was
+// sent by the client and could be made up
+fn forwarded_for(headers: &HeaderMap) -> Option<IpAddr> {
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .next_back()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts
+            .extensions
+            .get::<ClientIp>()
+            .copied()
+            .unwrap_or(ClientIp(None)))
+    }
+}
@@ -0,0 +1,464 @@
+use std::{
+    env,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+
+use tracing::{error, warn, Level};
+
+use crate::{
+    access_log::{AccessLogConfig, AccessLogFormat},
+    backup::BackupConfig,
+    mail::SmtpConfig,
+    mx::MxCheckConfig,
+    session::{self, LoginConfig},
+};
+
+// Runtime settings, read once at startup from environment variables and command line flags
+pub struct Config {
+    // Block renders taking longer than this get logged at warn level
+    pub slow_render_threshold: Duration,
+    // Maximum number of rendered fragments kept in the fragment cache
+    pub fragment_cache_size: usize,
+    // Number of contacts per page when the request doesn't ask for a page size
+    pub contacts_per_page: usize,
+    // Fuzzy search results scoring below this are left out (FUZZY_SEARCH_THRESHOLD)
+    pub fuzzy_search_threshold: i64,
+    // Maximum number of fuzzy search results (FUZZY_SEARCH_LIMIT)
+    pub fuzzy_search_limit: usize,
+    // Names at least this many percent alike are suggested as duplicates
+    // (DUPLICATE_NAME_SIMILARITY), see GET /contacts/duplicates
+    pub duplicate_name_similarity: u8,
+    // How long browsers may cache static files that aren't fingerprinted (STATIC_MAX_AGE_SECS)
+    pub static_max_age: Duration,
+    // Serves main.css under a URL with a hash of its content, cached for good
+    // (FINGERPRINT_ASSETS=0 turns it off). Off in dev mode, where tailwind rebuilds the file
+    // while the server runs.
+    pub fingerprint_assets: bool,
+    // Longest accepted contact name, email and notes, counted in characters
+    pub max_name_length: usize,
+    pub max_email_length: usize,
+    pub max_phone_length: usize,
+    pub max_notes_length: usize,
+    // Phone numbers are stored in E.164 form when set (PHONE_DEFAULT_COUNTRY, e.g. DE),
+    // numbers without a country code are read as numbers of this country
+    pub phone_default_country: Option<phonenumber::country::Id>,
+    // Most tags a single contact can carry
+    pub max_tags: usize,
+    // Longest accepted tag, counted in characters (MAX_TAG_LENGTH)
+    pub max_tag_length: usize,
+    // Most recent contact changes kept for the activity view (ACTIVITY_LOG_SIZE)
+    pub activity_log_size: usize,
+    // Most field changes kept per contact for its detail page (FIELD_HISTORY_SIZE)
+    pub field_history_size: usize,
+    // Most fields accepted by the forms that take lists of ids
+    pub max_form_fields: usize,
+    // Most file imports processed at once (MAX_CONCURRENT_IMPORTS), more are refused with 503
+    pub max_concurrent_imports: usize,
+    // Most requests handled at once (MAX_IN_FLIGHT), more are shed with 503 rather than
+    // queued. Static files have their own, higher limit (MAX_STATIC_IN_FLIGHT).
+    pub max_in_flight: usize,
+    pub max_static_in_flight: usize,
+    // Longest a long poll on /contacts/poll waits for a change (LONG_POLL_TIMEOUT_SECS)
+    pub long_poll_timeout: Duration,
+    // Take the client IP from the X-Forwarded-For header of a reverse proxy (TRUST_PROXY=1),
+    // only safe when every request comes through the proxy, since clients can send the header
+    pub trust_proxy: bool,
+    // Only one in this many successful requests gets its response logged by the trace layer
+    // (LOG_SAMPLE_EVERY), errors always are. 1 logs every request.
+    pub log_sample_every: u64,
+    // One line per request (ACCESS_LOG=compact or verbose), off by default
+    pub access_log: Option<AccessLogConfig>,
+    // Refuses changes to contacts with 403, for public demos (READ_ONLY=1). The counter
+    // keeps working, it shows off the app without piling up data.
+    pub read_only: bool,
+    // Route groups to serve, all of them unless FEATURES names the ones to keep
+    pub features: Features,
+    // Contacts and counter are saved to this JSON file when set (DATA_FILE)
+    pub data_file: Option<PathBuf>,
+    // Counters are also saved to this JSON file after every change (COUNTER_FILE)
+    pub counter_file: Option<PathBuf>,
+    // Value new counters start at (COUNTER_START)
+    pub counter_start: usize,
+    // Recent values kept per counter for the activity sparkline (COUNTER_HISTORY_SIZE)
+    pub counter_history_size: usize,
+    // How often pending changes are written to the data file
+    pub persist_interval: Duration,
+    // New contacts start unverified and get a verification link (EMAIL_VERIFICATION=1)
+    pub email_verification: bool,
+    // How long a verification link stays valid
+    pub verification_ttl: Duration,
+    // How often expired entries like verification tokens are swept
+    pub cleanup_interval: Duration,
+    // Base for absolute links in outgoing mail (PUBLIC_URL)
+    pub public_url: String,
+    // Verification links are mailed to new contacts, only enabled when SMTP_HOST is set
+    pub smtp: Option<SmtpConfig>,
+    // Periodic snapshot backups, only enabled when BACKUP_DIR is set
+    pub backup: Option<BackupConfig>,
+    // Settings for POST /contacts/verify-emails, only set with the mx-check feature
+    pub mx_check: Option<MxCheckConfig>,
+    // Every page but the login page needs a session, only enabled when LOGIN_PASSWORD is set
+    pub login: Option<LoginConfig>,
+    // TCP address to listen on (--addr), `::` listens on IPv4 and IPv6 where supported
+    pub bind_addr: SocketAddr,
+    // Serve on this Unix domain socket instead of the TCP port (--unix-socket)
+    #[cfg(unix)]
+    pub unix_socket: Option<PathBuf>,
+}
+
+impl Config {
+    // Reads the environment first, command line flags override it
+    pub fn load() -> Self {
+        let mut config = Self::from_env();
+        config.apply_args(env::args().skip(1));
+        config
+    }
+
+    pub fn from_env() -> Self {
+        // Dev mode (HTMX_DEV=1) enables the /admin debugging endpoints, never set it in production
+        let dev_mode = env::var("HTMX_DEV").is_ok_and(|value| value == "1");
+        let features = Features::from_env(dev_mode);
+        Self {
+            slow_render_threshold: Duration::from_millis(env_or("SLOW_RENDER_MS", 50)),
+            fragment_cache_size: env_or("FRAGMENT_CACHE_SIZE", 128),
+            contacts_per_page: env_or("CONTACTS_PER_PAGE", 20),
+            fuzzy_search_threshold: env_or("FUZZY_SEARCH_THRESHOLD", 40),
+            duplicate_name_similarity: env_or("DUPLICATE_NAME_SIMILARITY", 85),
+            fuzzy_search_limit: env_or("FUZZY_SEARCH_LIMIT", 20),
+            static_max_age: Duration::from_secs(env_or("STATIC_MAX_AGE_SECS", 7 * 24 * 60 * 60)),
+            fingerprint_assets: match env::var("FINGERPRINT_ASSETS") {
+                Ok(value) => value == "1",
+                Err(_) => !dev_mode,
+            },
+            max_name_length: env_or("MAX_NAME_LENGTH", 100),
+            max_email_length: env_or("MAX_EMAIL_LENGTH", 254),
+            max_phone_length: env_or("MAX_PHONE_LENGTH", 32),
+            max_notes_length: env_or("MAX_NOTES_LENGTH", 5000),
+            phone_default_country: env::var("PHONE_DEFAULT_COUNTRY").ok().and_then(|raw| {
+                raw.trim()
+                    .to_ascii_uppercase()
+                    .parse()
+                    .inspect_err(|_| {
+                        warn!("Ignoring invalid value {raw:?} for PHONE_DEFAULT_COUNTRY")
+                    })
+                    .ok()
+            }),
+            max_tags: env_or("MAX_TAGS", 20),
+            max_tag_length: env_or("MAX_TAG_LENGTH", 50),
+            max_form_fields: env_or("MAX_FORM_FIELDS", 1000),
+            max_concurrent_imports: env_or("MAX_CONCURRENT_IMPORTS", 2),
+            max_in_flight: env_or("MAX_IN_FLIGHT", 1024),
+            max_static_in_flight: env_or("MAX_STATIC_IN_FLIGHT", 4096),
+            long_poll_timeout: Duration::from_secs(env_or("LONG_POLL_TIMEOUT_SECS", 30)),
+            activity_log_size: env_or("ACTIVITY_LOG_SIZE", 200),
+            field_history_size: env_or("FIELD_HISTORY_SIZE", 20),
+            trust_proxy: env::var("TRUST_PROXY").is_ok_and(|value| value == "1"),
+            log_sample_every: env_or("LOG_SAMPLE_EVERY", 1),
+            access_log: access_log_config(dev_mode),
+            read_only: env::var("READ_ONLY").is_ok_and(|value| value == "1"),
+            features,
+            data_file: env::var("DATA_FILE").ok().map(PathBuf::from),
+            counter_file: env::var("COUNTER_FILE").ok().map(PathBuf::from),
+            counter_start: env_or("COUNTER_START", 0),
+            counter_history_size: env_or("COUNTER_HISTORY_SIZE", 100),
+            persist_interval: interval_or("PERSIST_INTERVAL_SECS", 5),
+            email_verification: env::var("EMAIL_VERIFICATION").is_ok_and(|value| value == "1"),
+            verification_ttl: Duration::from_secs(env_or("VERIFICATION_TTL_SECS", 24 * 60 * 60)),
+            cleanup_interval: interval_or("CLEANUP_INTERVAL_SECS", 5 * 60),
+            public_url: env::var("PUBLIC_URL")
+                .map(|url| url.trim_end_matches('/').to_string())
+                .unwrap_or_else(|_| format!("http://localhost:{DEFAULT_PORT}")),
+            smtp: env::var("SMTP_HOST").ok().map(|host| SmtpConfig {
+                host,
+                port: env_or("SMTP_PORT", 587),
+                username: env::var("SMTP_USERNAME").ok(),
+                password: env::var("SMTP_PASSWORD").ok(),
+                from: env::var("SMTP_FROM").unwrap_or_else(|_| "noreply@localhost".to_string()),
+                max_attempts: env_or("SMTP_MAX_ATTEMPTS", 5),
+                dead_letter_file: env::var("SMTP_DEAD_LETTER_FILE").ok().map(PathBuf::from),
+            }),
+            backup: env::var("BACKUP_DIR").ok().map(|dir| BackupConfig {
+                dir: PathBuf::from(dir),
+                interval: interval_or("BACKUP_INTERVAL_SECS", 60 * 60),
+                retention: retention_days(env_or("BACKUP_RETENTION_DAYS", 7)),
+            }),
+            mx_check: features.mx_check.then(|| MxCheckConfig {
+                concurrency: env_or("MX_CHECK_CONCURRENCY", 8),
+                min_interval: Duration::from_secs(env_or("MX_CHECK_INTERVAL_SECS", 5 * 60)),
+            }),
+            login: login_password_hash().map(|password_hash| LoginConfig {
+                password_hash,
+                timeout: Duration::from_secs(env_or("SESSION_TIMEOUT_SECS", 30 * 60)),
+                sliding: env::var("SESSION_SLIDING").map_or(true, |value| value != "0"),
+            }),
+            bind_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), DEFAULT_PORT),
+            #[cfg(unix)]
+            unix_socket: None,
+        }
+    }
+
+    // Accepts both `--flag value` and `--flag=value`
+    fn apply_args(&mut self, args: impl IntoIterator<Item = String>) {
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let (flag, inline_value) = match arg.split_once('=') {
+                Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+                None => (arg, None),
+            };
+            let mut value = || inline_value.clone().or_else(|| args.next());
+            match flag.as_str() {
+                "--addr" => match value().as_deref().and_then(parse_bind_addr) {
+                    Some(addr) => self.bind_addr = addr,
+                    None => warn!("--addr needs an address like 0.0.0.0, [::] or [::1]:8080"),
+                },
+                #[cfg(unix)]
+                "--unix-socket" => match value() {
+                    Some(path) => self.unix_socket = Some(path.into()),
+                    None => warn!("--unix-socket needs a path"),
+                },
+                _ => warn!("Ignoring unknown argument {flag:?}"),
+            }
+        }
+    }
+}
+
+// A broken hash is fatal, starting without a login would open the app to everyone
+fn login_password_hash() -> Option<String> {
+    if let Ok(hash) = env::var("LOGIN_PASSWORD_HASH") {
+        if !session::is_password_hash(&hash) {
+            error!("Invalid LOGIN_PASSWORD_HASH, expected the output of --hash-password");
+            std::process::exit(1);
+        }
+        return Some(hash);
+    }
+    env::var("LOGIN_PASSWORD")
+        .ok()
+        .map(|password| session::hash_password(&password))
+}
+
+fn access_log_config(dev_mode: bool) -> Option<AccessLogConfig> {
+    let format = match env::var("ACCESS_LOG").as_deref() {
+        Ok("compact") => AccessLogFormat::Compact,
+        Ok("verbose") => AccessLogFormat::Verbose,
+        Ok(other) => {
+            warn!("Ignoring unknown ACCESS_LOG {other:?}, expected compact or verbose");
+            return None;
+        }
+        Err(_) => return None,
+    };
+    // Form bodies hold personal data, so they're never logged outside of dev mode
+    let bodies = env::var("ACCESS_LOG_BODIES").is_ok_and(|value| value == "1");
+    if bodies && !dev_mode {
+        warn!("Ignoring ACCESS_LOG_BODIES, it needs HTMX_DEV=1");
+    }
+    Some(AccessLogConfig {
+        format,
+        bodies: bodies && dev_mode,
+    })
+}
+
+// Whole route groups that can be switched off, e.g. FEATURES=contacts for a deployment
+// without the counter. The index page, static files and the probes are always served.
+#[derive(Clone, Copy)]
+pub struct Features {
+    pub counter: bool,
+    pub contacts: bool,
+    // The /admin debugging endpoints, never without dev mode
+    pub admin: bool,
+    // POST /contacts/verify-emails, which makes DNS queries for the whole list, so it's only
+    // on when listed in FEATURES or with MX_CHECK=1
+    pub mx_check: bool,
+}
+
+impl Features {
+    fn from_env(dev_mode: bool) -> Self {
+        let mut features = match env::var("FEATURES") {
+            Ok(raw) => {
+                let mut features = Self {
+                    counter: false,
+                    contacts: false,
+                    admin: false,
+                    mx_check: false,
+                };
+                for name in raw
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                {
+                    match name {
+                        "counter" => features.counter = true,
+                        "contacts" => features.contacts = true,
+                        "admin" if dev_mode => features.admin = true,
+                        "admin" => warn!("Ignoring feature admin, it needs HTMX_DEV=1"),
+                        "mx-check" => features.mx_check = true,
+                        other => warn!("Ignoring unknown feature {other:?}"),
+                    }
+                }
+                features
+            }
+            Err(_) => Self {
+                counter: true,
+                contacts: true,
+                admin: dev_mode,
+                mx_check: env::var("MX_CHECK").is_ok_and(|value| value == "1"),
+            },
+        };
+        if features.mx_check && !features.contacts {
+            warn!("Ignoring feature mx-check, it needs the contacts feature");
+            features.mx_check = false;
+        }
+        features
+    }
+
+    // Names as written in FEATURES, for the startup log
+    pub fn enabled(&self) -> Vec<&'static str> {
+        [
+            ("counter", self.counter),
+            ("contacts", self.contacts),
+            ("admin", self.admin),
+            ("mx-check", self.mx_check),
+        ]
+        .into_iter()
+        .filter_map(|(name, enabled)| enabled.then_some(name))
+        .collect()
+    }
+}
+
+// Logging is set up before the rest of the config is read so config warnings end up in the log
+pub struct LogConfig {
+    // LOG_FORMAT=json switches to one JSON object per line for log aggregators
+    pub json: bool,
+    // LOG_LEVEL, e.g. info or debug
+    pub level: Level,
+}
+
+impl LogConfig {
+    pub fn from_env() -> Self {
+        let json = match env::var("LOG_FORMAT").as_deref() {
+            Ok("json") => true,
+            Ok("pretty") | Err(_) => false,
+            Ok(other) => {
+                eprintln!("Ignoring unknown LOG_FORMAT {other:?}, expected json or pretty");
+                false
+            }
+        };
+        let level = match env::var("LOG_LEVEL") {
+            Ok(raw) => raw.parse().unwrap_or_else(|_| {
+                eprintln!("Ignoring invalid LOG_LEVEL {raw:?}");
+                Level::DEBUG
+            }),
+            Err(_) => Level::DEBUG,
+        };
+        Self { json, level }
+    }
+}
+
+// Port used when --addr only names an IP address
+const DEFAULT_PORT: u16 = 1337;
+
+// Parses `ip`, `ip:port`, `[ipv6]` or `[ipv6]:port`, a bare IPv6 literal like `::` also works
+fn parse_bind_addr(raw: &str) -> Option<SocketAddr> {
+    let raw = raw.trim();
+    if let Ok(addr) = raw.parse::<SocketAddr>() {
+        return Some(addr);
+    }
+    let ip = raw
+        .strip_prefix('[')
+        .and_then(|ip| ip.strip_suffix(']'))
+        .unwrap_or(raw);
+    ip.parse::<IpAddr>()
+        .ok()
+        .map(|ip| SocketAddr::new(ip, DEFAULT_PORT))
+}
+
+// Like env_or for a number of seconds between runs of a task, which can't be zero
+fn interval_or(key: &str, default_secs: u64) -> Duration {
+    match env_or(key, default_secs) {
+        0 => {
+            warn!("Ignoring {key}=0, the interval has to be at least a second");
+            Duration::from_secs(default_secs)
+        }
+        secs => Duration::from_secs(secs),
+    }
+}
+
+// None when the days don't fit a Duration, backups are kept for good then
+fn retention_days(days: u64) -> Option<Duration> {
+    let retention = days.checked_mul(24 * 60 * 60).map(Duration::from_secs);
+    if retention.is_none() {
+        warn!("BACKUP_RETENTION_DAYS={days} is too large, backups won't be pruned");
+    }
+    retention
+}
+
+// Reads and parses an environment variable, falling back to the default when unset or invalid
+fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    match env::var(key) {
+        Ok(raw) => raw.parse().unwrap_or_else(|_| {
+            warn!("Ignoring invalid value {raw:?} for {key}");
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    #[test]
+    fn parses_ipv4_addresses() {
+        assert_eq!(
+            parse_bind_addr("0.0.0.0"),
+            Some(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), DEFAULT_PORT))
+        );
+        assert_eq!(
+            parse_bind_addr("127.0.0.1:8080"),
+            Some(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 8080))
+        );
+    }
+
+    #[test]
+    fn parses_ipv6_addresses() {
+        let any = Some(SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), DEFAULT_PORT));
+        assert_eq!(parse_bind_addr("::"), any);
+        assert_eq!(parse_bind_addr("[::]"), any);
+        assert_eq!(
+            parse_bind_addr("[::1]:8080"),
+            Some(SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 8080))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_addresses() {
+        assert_eq!(parse_bind_addr("localhost"), None);
+        assert_eq!(parse_bind_addr("[0.0.0.0"), None);
+        assert_eq!(parse_bind_addr("::1:8080:"), None);
+    }
+
+    #[test]
+    fn zero_intervals_fall_back_to_the_default() {
+        // Names of their own, tests run in parallel and share the environment
+        env::set_var("TEST_ZERO_INTERVAL_SECS", "0");
+        env::set_var("TEST_SHORT_INTERVAL_SECS", "2");
+        assert_eq!(
+            interval_or("TEST_ZERO_INTERVAL_SECS", 60),
+            Duration::from_secs(60)
+        );
+        assert_eq!(
+            interval_or("TEST_SHORT_INTERVAL_SECS", 60),
+            Duration::from_secs(2)
+        );
+    }
+
+    #[test]
+    fn huge_retentions_keep_backups() {
+        assert_eq!(retention_days(1), Some(Duration::from_secs(24 * 60 * 60)));
+        assert_eq!(retention_days(u64::MAX), None);
+    }
+}
@@ -0,0 +1,55 @@
+use std::fmt::Write;
+
+use axum::http::{header, HeaderMap};
+
+// Reads a cookie set by `set`, undoing its percent-encoding
+pub fn get(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .and_then(|(_, value)| decode(value))
+}
+
+// Set-Cookie value for a site-wide session cookie that scripts can't read. Values are
+// percent-encoded since cookies only allow a subset of ASCII.
+pub fn set(name: &str, value: &str) -> String {
+    format!("{name}={}; Path=/; HttpOnly; SameSite=Lax", encode(value))
+}
+
+// Set-Cookie value that makes the browser drop the cookie right away
+pub fn clear(name: &str) -> String {
+    format!("{name}=; Path=/; Max-Age=0; HttpOnly; SameSite=Lax")
+}
+
+fn encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            let _ = write!(encoded, "%{byte:02X}");
+        }
+    }
+    encoded
+}
+
+// None for malformed escapes or bytes that aren't UTF-8, e.g. a cookie set by someone else
+fn decode(value: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
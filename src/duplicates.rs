@@ -0,0 +1,104 @@
+use crate::{import::normalize_email, Contact};
+
+// Contacts that are likely the same person, for offering a merge. Nothing is merged here.
+pub struct DuplicateGroup<'a> {
+    pub contacts: Vec<&'a Contact>,
+    // Some pair in the group shares an email, otherwise they only have similar names
+    pub same_email: bool,
+}
+
+// Groups contacts with the same normalized email or with names at least `min_similarity`
+// percent alike, see `name_similarity`. Similarity chains, so A ~ B and B ~ C end up in one
+// group. Compares every pair, which is fine for an address book but not for huge lists.
+pub fn find(contacts: &[Contact], min_similarity: u8) -> Vec<DuplicateGroup<'_>> {
+    let emails: Vec<String> = contacts
+        .iter()
+        .map(|contact| normalize_email(&contact.email))
+        .collect();
+    let names: Vec<Vec<char>> = contacts
+        .iter()
+        .map(|contact| normalize_name(&contact.name))
+        .collect();
+
+    // Union-find over contact indices, each group is keyed by its root
+    let mut parents: Vec<usize> = (0..contacts.len()).collect();
+    let mut same_email = vec![false; contacts.len()];
+    for a in 0..contacts.len() {
+        for b in a + 1..contacts.len() {
+            let email_match = !emails[a].is_empty() && emails[a] == emails[b];
+            if !email_match && name_similarity(&names[a], &names[b]) < min_similarity {
+                continue;
+            }
+            let (root_a, root_b) = (root(&mut parents, a), root(&mut parents, b));
+            parents[root_b] = root_a;
+            same_email[root_a] |= email_match || same_email[root_b];
+        }
+    }
+
+    let mut groups: Vec<(usize, DuplicateGroup)> = Vec::new();
+    for (index, contact) in contacts.iter().enumerate() {
+        let group_root = root(&mut parents, index);
+        match groups.iter_mut().find(|(key, _)| *key == group_root) {
+            Some((_, group)) => group.contacts.push(contact),
+            None => groups.push((
+                group_root,
+                DuplicateGroup {
+                    contacts: vec![contact],
+                    same_email: false,
+                },
+            )),
+        }
+    }
+    groups
+        .into_iter()
+        .filter(|(_, group)| group.contacts.len() > 1)
+        .map(|(group_root, mut group)| {
+            group.same_email = same_email[group_root];
+            group
+        })
+        .collect()
+}
+
+fn root(parents: &mut [usize], mut index: usize) -> usize {
+    while parents[index] != index {
+        parents[index] = parents[parents[index]];
+        index = parents[index];
+    }
+    index
+}
+
+// Lowercased with runs of whitespace collapsed, so spacing and case don't count as edits
+fn normalize_name(name: &str) -> Vec<char> {
+    name.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+        .chars()
+        .collect()
+}
+
+// 100 for equal names, less the larger share of the longer name that has to be edited.
+// Empty names are never alike.
+fn name_similarity(a: &[char], b: &[char]) -> u8 {
+    if a.is_empty() || b.is_empty() {
+        return 0;
+    }
+    let longer = a.len().max(b.len());
+    let distance = edit_distance(a, b);
+    (100 - distance * 100 / longer) as u8
+}
+
+// Levenshtein distance, the fewest insertions, deletions and substitutions turning a into b
+fn edit_distance(a: &[char], b: &[char]) -> usize {
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+    for (i, a_char) in a.iter().enumerate() {
+        current[0] = i + 1;
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != b_char);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    previous[b.len()]
+}
@@ -0,0 +1,378 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering::Relaxed},
+    time::Duration,
+};
+
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{Html, IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use serde_json::json;
+use tracing::error;
+
+// Every error response goes through here so it gets counted, see `error_counts`
+pub enum AppError {
+    // Details are logged, the client only gets a generic message
+    Template(minijinja::Error),
+    // The body is shown in place of the submitted input, usually the form with its errors
+    Validation(String),
+    // A required part of the request is missing, e.g. the confirmation for a destructive action
+    BadRequest(String),
+    NotFound(String),
+    // The request isn't allowed, e.g. a change in read-only mode
+    Forbidden(String),
+    // Like Validation, for input that clashes with existing data
+    Conflict(String),
+    // The request has to wait this long before it can be repeated
+    RateLimited {
+        body: String,
+        retry_after: Duration,
+    },
+    // The server is at capacity for this kind of request, nothing was done
+    Busy(String),
+    // The request body is over the limit, e.g. an uploaded file
+    TooLarge(String),
+    // Any of the above for a change the page already shows, see `restore`
+    Restore {
+        error: Box<AppError>,
+        target: String,
+        fragment: String,
+    },
+}
+
+impl AppError {
+    // Swaps `fragment` in place of the `target` selector instead of the request's own target,
+    // so an optimistic change like a dragged row doesn't stay on the page after a rejection.
+    // The fragment should be the element as the server still has it.
+    pub fn restore(self, target: &str, fragment: String) -> Self {
+        Self::Restore {
+            error: Box::new(self),
+            target: target.to_string(),
+            fragment,
+        }
+    }
+}
+
+// What an error body is sent as, picked per request by `negotiate_format`
+#[derive(Clone, Copy, PartialEq)]
+pub enum ErrorFormat {
+    // The fragment as rendered, for htmx and browsers
+    Html,
+    // `{"error": "..."}` for API clients
+    Json,
+    // The fragment's text without markup
+    Text,
+}
+
+impl ErrorFormat {
+    // htmx always gets HTML, other clients what their Accept header asks for first
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        if headers.contains_key("hx-request") {
+            return Self::Html;
+        }
+        let accept = headers
+            .get(header::ACCEPT)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        accept
+            .split(',')
+            .filter_map(|media_range| media_range.split(';').next())
+            .find_map(|media_type| match media_type.trim() {
+                "application/json" => Some(Self::Json),
+                "text/html" | "application/xhtml+xml" => Some(Self::Html),
+                "text/plain" => Some(Self::Text),
+                _ => None,
+            })
+            .unwrap_or(Self::Text)
+    }
+}
+
+tokio::task_local! {
+    static FORMAT: ErrorFormat;
+}
+
+// Makes the request's error format known to `AppError::into_response`, which only gets to
+// see the error, not the request
+pub async fn negotiate_format(request: Request, next: Next) -> Response {
+    let format = ErrorFormat::from_headers(request.headers());
+    FORMAT.scope(format, next.run(request)).await
+}
+
+// HTML outside of a request, e.g. for errors rendered in a background task
+fn current_format() -> ErrorFormat {
+    FORMAT
+        .try_with(|format| *format)
+        .unwrap_or(ErrorFormat::Html)
+}
+
+// Counted since startup, like the fragment cache hits and misses
+static TEMPLATE_ERRORS: AtomicU64 = AtomicU64::new(0);
+static VALIDATION_ERRORS: AtomicU64 = AtomicU64::new(0);
+static BAD_REQUEST_ERRORS: AtomicU64 = AtomicU64::new(0);
+static NOT_FOUND_ERRORS: AtomicU64 = AtomicU64::new(0);
+static FORBIDDEN_ERRORS: AtomicU64 = AtomicU64::new(0);
+static CONFLICT_ERRORS: AtomicU64 = AtomicU64::new(0);
+static RATE_LIMITED_ERRORS: AtomicU64 = AtomicU64::new(0);
+static BUSY_ERRORS: AtomicU64 = AtomicU64::new(0);
+static TOO_LARGE_ERRORS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Serialize)]
+pub struct ErrorCounts {
+    template: u64,
+    validation: u64,
+    bad_request: u64,
+    not_found: u64,
+    forbidden: u64,
+    conflict: u64,
+    rate_limited: u64,
+    busy: u64,
+    too_large: u64,
+}
+
+pub fn error_counts() -> ErrorCounts {
+    ErrorCounts {
+        template: TEMPLATE_ERRORS.load(Relaxed),
+        validation: VALIDATION_ERRORS.load(Relaxed),
+        bad_request: BAD_REQUEST_ERRORS.load(Relaxed),
+        not_found: NOT_FOUND_ERRORS.load(Relaxed),
+        forbidden: FORBIDDEN_ERRORS.load(Relaxed),
+        conflict: CONFLICT_ERRORS.load(Relaxed),
+        rate_limited: RATE_LIMITED_ERRORS.load(Relaxed),
+        busy: BUSY_ERRORS.load(Relaxed),
+        too_large: TOO_LARGE_ERRORS.load(Relaxed),
+    }
+}
+
+impl From<minijinja::Error> for AppError {
+    fn from(err: minijinja::Error) -> Self {
+        Self::Template(err)
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        match self {
+            Self::Template(err) => {
+                TEMPLATE_ERRORS.fetch_add(1, Relaxed);
+                error!("Failed to render template: {err:#}");
+                error_body(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Something went wrong".to_string(),
+                )
+            }
+            Self::Validation(body) => {
+                VALIDATION_ERRORS.fetch_add(1, Relaxed);
+                error_body(StatusCode::UNPROCESSABLE_ENTITY, body)
+            }
+            Self::BadRequest(body) => {
+                BAD_REQUEST_ERRORS.fetch_add(1, Relaxed);
+                error_body(StatusCode::BAD_REQUEST, body)
+            }
+            Self::NotFound(body) => {
+                NOT_FOUND_ERRORS.fetch_add(1, Relaxed);
+                error_body(StatusCode::NOT_FOUND, body)
+            }
+            Self::Forbidden(body) => {
+                FORBIDDEN_ERRORS.fetch_add(1, Relaxed);
+                error_body(StatusCode::FORBIDDEN, body)
+            }
+            Self::Conflict(body) => {
+                CONFLICT_ERRORS.fetch_add(1, Relaxed);
+                error_body(StatusCode::CONFLICT, body)
+            }
+            Self::RateLimited { body, retry_after } => {
+                RATE_LIMITED_ERRORS.fetch_add(1, Relaxed);
+                // Rounded up, so retrying right at that time isn't refused again
+                let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+                (
+                    [(header::RETRY_AFTER, secs.to_string())],
+                    error_body(StatusCode::TOO_MANY_REQUESTS, body),
+                )
+                    .into_response()
+            }
+            Self::Busy(body) => {
+                BUSY_ERRORS.fetch_add(1, Relaxed);
+                error_body(StatusCode::SERVICE_UNAVAILABLE, body)
+            }
+            Self::TooLarge(body) => {
+                TOO_LARGE_ERRORS.fetch_add(1, Relaxed);
+                error_body(StatusCode::PAYLOAD_TOO_LARGE, body)
+            }
+            Self::Restore {
+                error,
+                target,
+                fragment,
+            } => {
+                // Keeps the status and gets counted as the wrapped error. Only htmx swaps
+                // the fragment, other clients get the error as it is.
+                let mut response = error.into_response();
+                if current_format() != ErrorFormat::Html {
+                    return response;
+                }
+                *response.body_mut() = Body::from(fragment);
+                let headers = response.headers_mut();
+                if let Ok(target) = HeaderValue::from_str(&target) {
+                    headers.insert("HX-Retarget", target);
+                }
+                headers.insert("HX-Reswap", HeaderValue::from_static("outerHTML"));
+                response
+            }
+        }
+    }
+}
+
+fn error_body(status: StatusCode, body: String) -> Response {
+    match current_format() {
+        ErrorFormat::Html => (status, Html(body)).into_response(),
+        ErrorFormat::Json => (status, Json(json!({ "error": plain_text(&body) }))).into_response(),
+        ErrorFormat::Text => (status, plain_text(&body)).into_response(),
+    }
+}
+
+// The text of an HTML fragment with tags dropped, entities decoded and whitespace collapsed
+fn plain_text(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(c) = rest.chars().next() {
+        match c {
+            '<' => match rest.find('>') {
+                Some(end) => {
+                    // Tags separate words, like the line breaks they usually stand for
+                    text.push(' ');
+                    rest = &rest[end + 1..];
+                    continue;
+                }
+                None => text.push(c),
+            },
+            '&' => {
+                if let Some((decoded, len)) = decode_entity(rest) {
+                    text.push(decoded);
+                    rest = &rest[len..];
+                    continue;
+                }
+                text.push(c);
+            }
+            _ => text.push(c),
+        }
+        rest = &rest[c.len_utf8()..];
+    }
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+// The character of an entity at the start of `html` and the entity's length, for the ones
+// minijinja's escaping produces
+fn decode_entity(html: &str) -> Option<(char, usize)> {
+    let end = html.find(';').filter(|end| *end <= 10)?;
+    let decoded = match &html[1..end] {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        entity => {
+            let code = match entity
+                .strip_prefix("#x")
+                .or_else(|| entity.strip_prefix("#X"))
+            {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => entity.strip_prefix('#')?.parse().ok()?,
+            };
+            char::from_u32(code)?
+        }
+    };
+    Some((decoded, end + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{middleware, routing::get, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    // The same validation error for every client
+    async fn reject() -> AppError {
+        AppError::Validation("<p class=\"error\">Name &amp; email are required</p>".to_string())
+    }
+
+    async fn get_error(headers: &[(&str, &str)]) -> (StatusCode, Option<String>, String) {
+        let app = Router::new()
+            .route("/", get(reject))
+            .layer(middleware::from_fn(negotiate_format));
+        let mut request = Request::get("/");
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let response = app
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .map(|value| value.to_str().unwrap().to_string());
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (
+            status,
+            content_type,
+            String::from_utf8(body.to_vec()).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn json_client_gets_a_json_error() {
+        let (status, content_type, body) = get_error(&[("accept", "application/json")]).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(content_type.as_deref(), Some("application/json"));
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body, json!({ "error": "Name & email are required" }));
+    }
+
+    #[tokio::test]
+    async fn htmx_client_gets_the_html_fragment() {
+        // HX-Request wins over the Accept header
+        let (status, content_type, body) =
+            get_error(&[("hx-request", "true"), ("accept", "application/json")]).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(content_type.as_deref(), Some("text/html; charset=utf-8"));
+        assert_eq!(body, "<p class=\"error\">Name &amp; email are required</p>");
+    }
+
+    #[tokio::test]
+    async fn other_clients_get_plain_text() {
+        let (status, content_type, body) = get_error(&[("accept", "*/*")]).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(content_type.as_deref(), Some("text/plain; charset=utf-8"));
+        assert_eq!(body, "Name & email are required");
+    }
+
+    #[test]
+    fn accept_picks_the_first_known_type() {
+        let format = |accept: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT, accept.parse().unwrap());
+            ErrorFormat::from_headers(&headers)
+        };
+        assert!(format("text/html,application/json") == ErrorFormat::Html);
+        assert!(format("image/webp, application/json;q=0.9") == ErrorFormat::Json);
+        assert!(format("text/plain") == ErrorFormat::Text);
+        assert!(ErrorFormat::from_headers(&HeaderMap::new()) == ErrorFormat::Text);
+    }
+
+    #[test]
+    fn plain_text_drops_markup_and_decodes_entities() {
+        assert_eq!(
+            plain_text("<ul><li>Tom &amp; Jerry</li><li>&#39;quoted&#x27;</li></ul>"),
+            "Tom & Jerry 'quoted'"
+        );
+        assert_eq!(plain_text("1 < 2 &unknown; 3"), "1 < 2 &unknown; 3");
+    }
+}
@@ -0,0 +1,33 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+// A contact field as it was before and after an edit
+#[derive(Clone, Serialize, Deserialize)]
+pub struct FieldChange {
+    pub field: String,
+    pub old: String,
+    pub new: String,
+    // Seconds since the Unix epoch
+    pub at: u64,
+}
+
+// Appends the change, dropping the oldest ones past `capacity`. An edit that keeps the value
+// isn't a change.
+pub fn record(history: &mut Vec<FieldChange>, capacity: usize, field: &str, old: &str, new: &str) {
+    if old == new || capacity == 0 {
+        return;
+    }
+    let at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default();
+    history.push(FieldChange {
+        field: field.to_string(),
+        old: old.to_string(),
+        new: new.to_string(),
+        at,
+    });
+    let excess = history.len().saturating_sub(capacity);
+    history.drain(..excess);
+}
@@ -0,0 +1,78 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Write,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+// Size of the sparkline drawing area in SVG user units
+const SPARKLINE_WIDTH: f64 = 120.0;
+const SPARKLINE_HEIGHT: f64 = 30.0;
+
+// Recent values of every counter, each capped at the same number of points
+pub struct CounterHistory {
+    capacity: usize,
+    series: HashMap<String, VecDeque<HistoryPoint>>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct HistoryPoint {
+    // Milliseconds since the Unix epoch
+    pub at: u64,
+    pub count: usize,
+}
+
+impl CounterHistory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            series: HashMap::new(),
+        }
+    }
+
+    // Drops the oldest point once the counter's series is full
+    pub fn push(&mut self, name: &str, count: usize) {
+        if self.capacity == 0 {
+            return;
+        }
+        let series = self.series.entry(name.to_string()).or_default();
+        if series.len() == self.capacity {
+            series.pop_front();
+        }
+        let at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or_default();
+        series.push_back(HistoryPoint { at, count });
+    }
+
+    // Oldest first, empty for counters that haven't changed yet
+    pub fn points(&self, name: &str) -> Vec<HistoryPoint> {
+        self.series
+            .get(name)
+            .map(|series| series.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+// Draws the values as a polyline, spread evenly left to right and scaled to the tallest value
+pub fn sparkline(points: &[HistoryPoint]) -> String {
+    let max = points
+        .iter()
+        .map(|point| point.count)
+        .max()
+        .unwrap_or(0)
+        .max(1) as f64;
+    let step = SPARKLINE_WIDTH / points.len().saturating_sub(1).max(1) as f64;
+    let mut coordinates = String::new();
+    for (i, point) in points.iter().enumerate() {
+        let x = i as f64 * step;
+        let y = SPARKLINE_HEIGHT - point.count as f64 / max * SPARKLINE_HEIGHT;
+        let _ = write!(coordinates, "{x:.1},{y:.1} ");
+    }
+    format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{SPARKLINE_WIDTH}" height="{SPARKLINE_HEIGHT}" viewBox="0 0 {SPARKLINE_WIDTH} {SPARKLINE_HEIGHT}"><polyline fill="none" stroke="currentColor" stroke-width="1.5" points="{}"/></svg>"#,
+        coordinates.trim_end()
+    )
+}
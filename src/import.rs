@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{birthday::unescape_text, Contact};
+
+// One contact as read from an import file
+#[derive(Default)]
+pub struct ImportRecord {
+    pub name: String,
+    pub email: String,
+    // As written in the file, normalized like the form's when the contact is added
+    pub phone: String,
+}
+
+// Reads vCard when the data starts like one, CSV with name, email and phone columns otherwise
+pub fn parse(data: &str) -> Result<Vec<ImportRecord>, String> {
+    if data
+        .trim_start()
+        .to_ascii_uppercase()
+        .starts_with("BEGIN:VCARD")
+    {
+        Ok(parse_vcard(data))
+    } else {
+        parse_csv(data)
+    }
+}
+
+// A header row naming the name and email columns, and optionally phone, is optional. Without
+// one the first three columns are taken as name, email and phone.
+fn parse_csv(data: &str) -> Result<Vec<ImportRecord>, String> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(data.as_bytes());
+    let mut rows = reader.records();
+    let mut records = Vec::new();
+    let (mut name_col, mut email_col, mut phone_col) = (0, 1, Some(2));
+    let mut first = true;
+    for row in &mut rows {
+        let row = row.map_err(|err| format!("Invalid CSV: {err}"))?;
+        if first {
+            first = false;
+            let position = |column: &str| row.iter().position(|c| c.eq_ignore_ascii_case(column));
+            if let (Some(name), Some(email)) = (position("name"), position("email")) {
+                (name_col, email_col, phone_col) = (name, email, position("phone"));
+                continue;
+            }
+        }
+        let column = |col: Option<usize>| {
+            col.and_then(|col| row.get(col))
+                .unwrap_or_default()
+                .to_string()
+        };
+        records.push(ImportRecord {
+            name: column(Some(name_col)),
+            email: column(Some(email_col)),
+            phone: column(phone_col),
+        });
+    }
+    Ok(records)
+}
+
+// Takes the FN, the first EMAIL and the first TEL of every card, other properties are ignored
+fn parse_vcard(data: &str) -> Vec<ImportRecord> {
+    // Lines starting with whitespace continue the previous line
+    let unfolded = data
+        .replace("\r\n", "\n")
+        .replace("\n ", "")
+        .replace("\n\t", "");
+    let mut records = Vec::new();
+    let mut current: Option<ImportRecord> = None;
+    for line in unfolded.lines() {
+        let Some((property, value)) = line.split_once(':') else {
+            continue;
+        };
+        // Parameters like `EMAIL;TYPE=work` don't matter here
+        let property = property.split(';').next().unwrap_or_default();
+        match property.to_ascii_uppercase().as_str() {
+            "BEGIN" => current = Some(ImportRecord::default()),
+            "END" => records.extend(current.take()),
+            "FN" => {
+                if let Some(record) = &mut current {
+                    record.name = unescape_text(value.trim());
+                }
+            }
+            "EMAIL" => {
+                if let Some(record) = current.as_mut().filter(|record| record.email.is_empty()) {
+                    record.email = unescape_text(value.trim());
+                }
+            }
+            "TEL" => {
+                if let Some(record) = current.as_mut().filter(|record| record.phone.is_empty()) {
+                    // vCard 4 writes numbers as URIs, like tel:+1-555-0100
+                    let value = unescape_text(value.trim());
+                    record.phone = value.strip_prefix("tel:").unwrap_or(&value).to_string();
+                }
+            }
+            _ => {}
+        }
+    }
+    records
+}
+
+// Body of a JSON import, a bare array of contacts or the object the JSON export writes
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum JsonImport {
+    Contacts(Vec<Value>),
+    Export { contacts: Vec<Value> },
+}
+
+impl JsonImport {
+    // Reads every entry on its own so a malformed one doesn't reject the rest. Entries
+    // without an id get 0, which no contact has, so they're given a new one.
+    pub fn into_contacts(self) -> Vec<Result<Contact, String>> {
+        let (Self::Contacts(entries) | Self::Export { contacts: entries }) = self;
+        entries
+            .into_iter()
+            .map(|mut entry| {
+                if let Some(fields) = entry.as_object_mut() {
+                    fields.entry("id").or_insert(Value::from(0));
+                }
+                serde_json::from_value(entry).map_err(|err| err.to_string())
+            })
+            .collect()
+    }
+}
+
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+pub struct Deduplicated {
+    pub records: Vec<ImportRecord>,
+    // Records folded into an earlier one with the same email
+    pub merged: usize,
+    // Records without an email
+    pub invalid: usize,
+}
+
+// Normalizes the emails and collapses records sharing one into the first of them, which
+// takes the name and phone of a later duplicate if it has none itself
+pub fn dedupe(records: Vec<ImportRecord>) -> Deduplicated {
+    let mut unique: Vec<ImportRecord> = Vec::new();
+    let mut index_by_email = HashMap::new();
+    let (mut merged, mut invalid) = (0, 0);
+    for record in records {
+        let email = normalize_email(&record.email);
+        if email.is_empty() {
+            invalid += 1;
+            continue;
+        }
+        let name = record.name.trim().to_string();
+        let phone = record.phone.trim().to_string();
+        match index_by_email.get(&email) {
+            Some(&index) => {
+                let existing: &mut ImportRecord = &mut unique[index];
+                if existing.name.is_empty() {
+                    existing.name = name;
+                }
+                if existing.phone.is_empty() {
+                    existing.phone = phone;
+                }
+                merged += 1;
+            }
+            None => {
+                index_by_email.insert(email.clone(), unique.len());
+                unique.push(ImportRecord { name, email, phone });
+            }
+        }
+    }
+    Deduplicated {
+        records: unique,
+        merged,
+        invalid,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(records: &[ImportRecord]) -> Vec<(&str, &str, &str)> {
+        records
+            .iter()
+            .map(|record| (&*record.name, &*record.email, &*record.phone))
+            .collect()
+    }
+
+    #[test]
+    fn csv_columns_follow_the_header() {
+        let records = parse("Phone,Email,Name\n555 0100,ann@example.com,Ann\n").unwrap();
+        assert_eq!(fields(&records), [("Ann", "ann@example.com", "555 0100")]);
+
+        let records = parse("email,name\nbob@example.com,Bob\n").unwrap();
+        assert_eq!(fields(&records), [("Bob", "bob@example.com", "")]);
+    }
+
+    #[test]
+    fn csv_without_header_is_name_email_phone() {
+        let records = parse("Ann,ann@example.com,555 0100\nBob,bob@example.com\n").unwrap();
+        assert_eq!(
+            fields(&records),
+            [
+                ("Ann", "ann@example.com", "555 0100"),
+                ("Bob", "bob@example.com", ""),
+            ]
+        );
+    }
+
+    #[test]
+    fn vcard_takes_the_first_email_and_phone() {
+        let data = "BEGIN:VCARD\r\nFN:Ann\r\n  Lee\r\nEMAIL;TYPE=work:ann@example.com\r\n\
+                    EMAIL:other@example.com\r\nTEL;VALUE=uri:tel:+1-555-0100\r\n\
+                    TEL:+1-555-0199\r\nEND:VCARD\r\n";
+        assert_eq!(
+            fields(&parse(data).unwrap()),
+            [("Ann Lee", "ann@example.com", "+1-555-0100")]
+        );
+
+        // The export escapes TEXT values, reading them back undoes that
+        let mut contact = Contact::new("Lee, Ann; \\o/", "ann@example.com");
+        contact.phone = "+1-555-0100".to_string();
+        let exported = crate::vcard::card(&contact, false);
+        assert_eq!(
+            fields(&parse(&exported).unwrap()),
+            [("Lee, Ann; \\o/", "ann@example.com", "+1-555-0100")]
+        );
+    }
+
+    #[test]
+    fn dedupe_merges_by_normalized_email() {
+        let record = |name: &str, email: &str, phone: &str| ImportRecord {
+            name: name.to_string(),
+            email: email.to_string(),
+            phone: phone.to_string(),
+        };
+        let deduplicated = dedupe(vec![
+            record("", " Ann@Example.com", ""),
+            record("Ann", "ann@example.com", "555 0100"),
+            record("Bob", "", ""),
+        ]);
+        assert_eq!(
+            fields(&deduplicated.records),
+            [("Ann", "ann@example.com", "555 0100")]
+        );
+        assert_eq!((deduplicated.merged, deduplicated.invalid), (1, 1));
+    }
+}
@@ -0,0 +1,211 @@
+use std::{path::PathBuf, time::Duration};
+
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
+    AsyncTransport, Message, Tokio1Executor,
+};
+use serde::Serialize;
+use tokio::{fs::OpenOptions, io::AsyncWriteExt, sync::mpsc, task::JoinHandle, time};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+use crate::Contact;
+
+// SMTP port that expects TLS right away instead of upgrading with STARTTLS
+const IMPLICIT_TLS_PORT: u16 = 465;
+
+// Mails waiting to be sent, further mails are dropped with a warning while the queue is full
+const QUEUE_SIZE: usize = 1024;
+
+// Delay before the first retry, doubled after every failed attempt up to the maximum
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(5 * 60);
+
+// Settings for outgoing mail, mail is off unless a host is configured
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+    // Attempts per mail including the first one
+    pub max_attempts: u32,
+    // Mails that still failed after the last attempt are appended here as JSON lines
+    pub dead_letter_file: Option<PathBuf>,
+}
+
+// Handle for queueing mails, the worker does the actual sending
+pub struct Mailer {
+    queue: mpsc::Sender<OutgoingMail>,
+}
+
+// Sends the queued mails in the background, see `spawn`
+pub struct MailWorker {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+    queue: mpsc::Receiver<OutgoingMail>,
+    max_attempts: u32,
+    dead_letter_file: Option<PathBuf>,
+}
+
+#[derive(Serialize)]
+struct OutgoingMail {
+    to_name: String,
+    to_email: String,
+    subject: String,
+    body: String,
+}
+
+// Returns None with a warning when the settings can't be used, mail is then skipped
+pub fn queue(config: SmtpConfig) -> Option<(Mailer, MailWorker)> {
+    let from = match config.from.parse() {
+        Ok(from) => from,
+        Err(err) => {
+            warn!("Mail disabled, invalid SMTP_FROM {:?}: {err}", config.from);
+            return None;
+        }
+    };
+    let builder = if config.port == IMPLICIT_TLS_PORT {
+        AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host)
+    } else {
+        AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host)
+    };
+    let mut builder = match builder {
+        Ok(builder) => builder.port(config.port),
+        Err(err) => {
+            warn!("Mail disabled, invalid SMTP_HOST {:?}: {err}", config.host);
+            return None;
+        }
+    };
+    if let (Some(username), Some(password)) = (config.username, config.password) {
+        builder = builder.credentials(Credentials::new(username, password));
+    }
+    let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+    let worker = MailWorker {
+        transport: builder.build(),
+        from,
+        queue: receiver,
+        max_attempts: config.max_attempts.max(1),
+        dead_letter_file: config.dead_letter_file,
+    };
+    Some((Mailer { queue: sender }, worker))
+}
+
+impl Mailer {
+    // Only queues the mail, so the request never waits for the mail server
+    pub fn send_verification(&self, contact: &Contact, link: &str) {
+        let mail = OutgoingMail {
+            to_name: contact.name.clone(),
+            to_email: contact.email.clone(),
+            subject: "Please verify your email address".to_string(),
+            body: format!(
+                "Hi {},\n\nplease confirm your email address by opening this link:\n\n{link}\n",
+                contact.name
+            ),
+        };
+        if let Err(err) = self.queue.try_send(mail) {
+            warn!(contact_id = contact.id, "Dropped verification email: {err}");
+        }
+    }
+}
+
+impl MailWorker {
+    // Sends mails in queue order, retrying each with exponential backoff. On shutdown the
+    // mails still queued get one last attempt each instead of waiting out their backoff.
+    pub fn spawn(mut self, shutdown: CancellationToken) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                let mail = tokio::select! {
+                    mail = self.queue.recv() => mail,
+                    _ = shutdown.cancelled() => break,
+                };
+                let Some(mail) = mail else { break };
+                self.deliver(mail, &shutdown).await;
+            }
+            self.queue.close();
+            while let Some(mail) = self.queue.recv().await {
+                self.deliver(mail, &shutdown).await;
+            }
+            info!("Mail worker stopped");
+        })
+    }
+
+    async fn deliver(&self, mail: OutgoingMail, shutdown: &CancellationToken) {
+        let mut delay = RETRY_BASE_DELAY;
+        let mut attempt = 1;
+        let error = loop {
+            let error = match self.send(&mail).await {
+                Ok(()) => {
+                    debug!(attempt, "Sent email");
+                    return;
+                }
+                Err(err) => err,
+            };
+            if attempt >= self.max_attempts || shutdown.is_cancelled() {
+                break error;
+            }
+            warn!(
+                attempt,
+                "Failed to send email, retrying in {delay:?}: {error}"
+            );
+            tokio::select! {
+                _ = time::sleep(delay) => {},
+                _ = shutdown.cancelled() => {},
+            }
+            delay = (delay * 2).min(RETRY_MAX_DELAY);
+            attempt += 1;
+        };
+        warn!(attempt, "Giving up on email: {error}");
+        self.dead_letter(&mail, &error, attempt).await;
+    }
+
+    async fn send(&self, mail: &OutgoingMail) -> Result<(), String> {
+        let address = mail
+            .to_email
+            .parse()
+            .map_err(|err| format!("invalid address: {err}"))?;
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(Mailbox::new(Some(mail.to_name.clone()), address))
+            .subject(&mail.subject)
+            .body(mail.body.clone())
+            .map_err(|err| err.to_string())?;
+        self.transport
+            .send(message)
+            .await
+            .map(|_| ())
+            .map_err(|err| err.to_string())
+    }
+
+    async fn dead_letter(&self, mail: &OutgoingMail, error: &str, attempts: u32) {
+        let Some(path) = &self.dead_letter_file else {
+            return;
+        };
+        #[derive(Serialize)]
+        struct DeadLetter<'a> {
+            #[serde(flatten)]
+            mail: &'a OutgoingMail,
+            error: &'a str,
+            attempts: u32,
+        }
+        let mut line = serde_json::to_vec(&DeadLetter {
+            mail,
+            error,
+            attempts,
+        })
+        .expect("Dead letters always serialize");
+        line.push(b'\n');
+        let result = async {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .await?;
+            file.write_all(&line).await
+        }
+        .await;
+        if let Err(err) = result {
+            warn!("Failed to write dead letter to {}: {err}", path.display());
+        }
+    }
+}
@@ -1,28 +1,107 @@
+mod access_log;
+mod activity;
+mod assets;
+mod backup;
+mod birthday;
+mod boost;
+mod cache;
+mod cache_control;
+mod changes;
+mod cleanup;
+mod client_ip;
+mod config;
+mod cookies;
+mod duplicates;
+mod error;
+mod field_history;
+mod history;
+mod import;
+mod mail;
+mod mx;
+mod overload;
+mod pagination;
+mod persistence;
+mod phone;
+mod read_only;
+mod request_id;
+mod routes;
+mod search;
+mod session;
+mod shutdown;
+mod snapshot;
+mod sort;
+mod telemetry;
+mod triggers;
+mod vcard;
+mod verification;
+
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    fmt::Write,
     net::SocketAddr,
     sync::{
         atomic::{AtomicUsize, Ordering::SeqCst},
         Arc,
     },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use axum::{
-    extract::{Form, State},
-    http::StatusCode,
-    response::{Html, IntoResponse},
-    routing::{get, post},
-    Router,
+    body::Body,
+    error_handling::HandleErrorLayer,
+    extract::{
+        multipart::MultipartError,
+        rejection::{FormRejection, JsonRejection},
+        Form, FromRequest, Multipart, Path, Query, State,
+    },
+    http::{header, HeaderMap, HeaderValue, Request, StatusCode, Uri},
+    middleware,
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::{get, post, put},
+    Json, Router,
 };
 
-use minijinja::{context, path_loader, Environment, Value};
+use futures_util::{stream, StreamExt};
+use minijinja::{context, path_loader, AutoEscape, Environment, Value};
+use rand::seq::IndexedRandom;
 use serde::{Deserialize, Serialize};
-use tokio::{net::TcpListener, sync::Mutex};
+use serde_json::json;
+use tokio::{
+    net::TcpListener,
+    sync::{Mutex, Semaphore},
+};
+use tokio_util::sync::CancellationToken;
+use tower::{limit::GlobalConcurrencyLimitLayer, BoxError, ServiceBuilder, ServiceExt};
 use tower_http::{
     services::{ServeDir, ServeFile},
-    trace::TraceLayer,
+    set_header::SetResponseHeaderLayer,
+    trace::{DefaultOnResponse, OnResponse, TraceLayer},
 };
-use tracing::info;
+use tracing::{debug, error, info, instrument, warn, Span};
+
+use activity::{ActivityKind, ActivityLog};
+use birthday::Birthday;
+use boost::PageMode;
+use cache::FragmentCache;
+use changes::ContactChanges;
+use client_ip::ClientIp;
+use config::{Config, Features, LogConfig};
+use error::AppError;
+use field_history::FieldChange;
+use history::CounterHistory;
+use mail::Mailer;
+use mx::{MxChecker, MxStatus};
+use pagination::Pagination;
+use persistence::{CounterFile, Persistence};
+use phone::PhoneCheck;
+use routes::{RouteInfo, RouteRegistry};
+use search::SearchHit;
+use session::{Sessions, SESSION_COOKIE};
+use snapshot::Snapshot;
+use sort::ContactSorter;
+use telemetry::ResponseSampler;
+use triggers::Triggers;
+use verification::{TokenCheck, VerificationTokens};
 
 //abbreviations in my code:
 // tpl: template
@@ -30,261 +109,4351 @@ use tracing::info;
 
 #[tokio::main]
 async fn main() {
-    // Initilize tracing subscriber
-    tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::DEBUG)
-        .init();
+    if std::env::args().nth(1).as_deref() == Some("--hash-password") {
+        session::print_password_hash();
+        return;
+    }
+    // Initialize tracing subscriber
+    let log_config = LogConfig::from_env();
+    let tracer_provider = telemetry::init_tracing(&log_config);
 
-    let mut tpl_env = Environment::new();
-    tpl_env.set_loader(path_loader("templates/"));
+    let mut config = Config::load();
+    let backup_config = config.backup.take();
+    let mx_checker = config.mx_check.take().and_then(MxChecker::new);
+    let (mailer, mail_worker) = config.smtp.take().and_then(mail::queue).unzip();
+    let socket_addr = config.bind_addr;
+    #[cfg(unix)]
+    let unix_socket = config.unix_socket.clone();
+
+    // The fingerprinted URL when there is one, the plain one still serves the same file
+    let stylesheet_url = config
+        .fingerprint_assets
+        .then(assets::fingerprinted_stylesheet_url)
+        .flatten();
+    let features = config.features;
+    let tpl_env = template_env(
+        features,
+        stylesheet_url.unwrap_or(assets::STYLESHEET_URL),
+        mx_checker.is_some(),
+    );
+    // Fails now instead of on the first request that renders a page
+    let unloadable = unloadable_templates(&tpl_env);
+    if !unloadable.is_empty() {
+        error!(
+            "Core templates could not be loaded from {TEMPLATES_DIR}, start the server from the \
+             directory that contains it:\n  {}",
+            unloadable.join("\n  ")
+        );
+        std::process::exit(1);
+    }
+
+    let persist_interval = config.persist_interval;
+    let static_max_age = config.static_max_age;
+    let (max_in_flight, max_static_in_flight) = (config.max_in_flight, config.max_static_in_flight);
+    let cleanup_interval = config.cleanup_interval;
+    let trust_proxy = config.trust_proxy;
+    let access_log_config = config.access_log;
+    let log_sample_every = config.log_sample_every;
+    let app_state = Arc::new(AppState::new(tpl_env, config, mailer));
+
+    // Continue from the saved state if there is one, otherwise start with a demo contact
+    let saved = match &app_state.persistence {
+        Some(persistence) => persistence.load_from_disk().await,
+        None => None,
+    };
+    let (counts, contacts) = match saved {
+        Some(snapshot) => {
+            snapshot.reserve_ids();
+            (snapshot.counts(), snapshot.contacts)
+        }
+        None => (
+            HashMap::new(),
+            vec![Contact::new("John Doe", "johndoe@hotmail.com")],
+        ),
+    };
+    // The counter file is written on every change, so it's at least as recent as the data file
+    let counter_file = app_state.config.counter_file.clone().map(CounterFile::new);
+    let counts = match &counter_file {
+        Some(counter_file) => counter_file.load().await.unwrap_or(counts),
+        None => counts,
+    };
+    let counter_app_state = Arc::new(CounterAppState::new(
+        app_state.clone(),
+        counts,
+        counter_file,
+    ));
+    let contacts_app_state = Arc::new(ContactsAppState::new(
+        app_state.clone(),
+        contacts,
+        mx_checker,
+    ));
+
+    let shutdown = CancellationToken::new();
+    shutdown::cancel_on_signal(shutdown.clone());
+    let backup_task = backup_config.map(|backup_config| {
+        backup::spawn_backup_task(
+            backup_config,
+            counter_app_state.clone(),
+            contacts_app_state.clone(),
+            shutdown.clone(),
+        )
+    });
+
+    let mail_task = mail_worker.map(|mail_worker| mail_worker.spawn(shutdown.clone()));
+    let cleanup_task = cleanup::spawn_cleanup_task(
+        cleanup_interval,
+        contacts_app_state.clone(),
+        shutdown.clone(),
+    );
+    let flush_task = app_state.persistence.is_some().then(|| {
+        persistence::spawn_flush_task(
+            persist_interval,
+            counter_app_state.clone(),
+            contacts_app_state.clone(),
+            shutdown.clone(),
+        )
+    });
 
-    let contacts = vec![Contact::new("John Doe", "johndoe@hotmail.com")];
-    let app_state = Arc::new(AppState::new(tpl_env));
-    let counter_app_state = Arc::new(CounterAppState::new(app_state.clone(), 0));
-    let contacts_app_state = Arc::new(ContactsAppState::new(app_state.clone(), contacts));
+    info!("Enabled features: {}", features.enabled().join(", "));
+    let read_only = app_state.config.read_only;
+    if read_only {
+        info!("Read-only mode, changes to contacts are refused");
+    }
+    let read_only_state = app_state.clone();
+    let refuse_changes = move |router: Router| {
+        if read_only {
+            router.layer(middleware::from_fn_with_state(
+                read_only_state.clone(),
+                read_only::reject_changes,
+            ))
+        } else {
+            router
+        }
+    };
+    if let Some(login) = &app_state.config.login {
+        info!(timeout = ?login.timeout, sliding = login.sliding, "Login required");
+    }
+    let session_state = app_state.clone();
+    let mut routes = RouteRegistry::default();
+
+    // Only with LOGIN_PASSWORD, otherwise nothing asks for a session
+    let login_router = if app_state.sessions.is_some() {
+        Router::new()
+            .route(
+                routes.add("GET, POST", "/login"),
+                get(login_page_handler).post(login_handler),
+            )
+            .route(routes.add("POST", "/logout"), post(logout_handler))
+            .with_state(app_state.clone())
+    } else {
+        Router::new()
+    };
 
     let root_router = Router::new()
-        .route("/", get(index_handler))
-        .with_state(app_state);
+        .route(routes.add("GET", "/"), get(index_handler))
+        .with_state(app_state.clone());
 
-    let counter_router = Router::new()
-        .route("/counter", get(counter_handler))
-        .route("/counter/increment", post(increment_handler))
-        .with_state(counter_app_state);
+    let counter_router = if features.counter {
+        Router::new()
+            .route(routes.add("GET", "/counter"), get(counter_handler))
+            .route(
+                routes.add("POST", "/counter/increment"),
+                post(increment_handler),
+            )
+            .route(
+                routes.add("POST", "/counter/decrement"),
+                post(decrement_handler),
+            )
+            .route(routes.add("POST", "/counter/reset"), post(reset_handler))
+            .route(
+                routes.add("GET", "/counter/history"),
+                get(counter_history_handler),
+            )
+            .route(
+                routes.add("GET", "/counter/{name}"),
+                get(named_counter_handler),
+            )
+            .route(
+                routes.add("POST", "/counter/{name}/increment"),
+                post(named_increment_handler),
+            )
+            .route(
+                routes.add("POST", "/counter/{name}/decrement"),
+                post(named_decrement_handler),
+            )
+            .route(
+                routes.add("POST", "/counter/{name}/reset"),
+                post(named_reset_handler),
+            )
+            .route(
+                routes.add("GET", "/counter/{name}/history"),
+                get(named_counter_history_handler),
+            )
+            .with_state(counter_app_state.clone())
+    } else {
+        Router::new()
+    };
 
-    let contacts_router = Router::new()
-        .route("/contacts", get(contacts_handler))
-        .route("/contact", post(add_contact_handler))
-        .route("/contact/{id}", post(add_contact_handler))
-        .with_state(contacts_app_state)
-        .fallback(not_found_handler);
+    let contacts_router = if features.contacts {
+        Router::new()
+            .route(routes.add("GET", "/contacts"), get(contacts_handler))
+            .route(
+                routes.add("GET", "/contacts/stream"),
+                get(contacts_stream_handler),
+            )
+            .route(
+                routes.add("GET", "/contacts/recent"),
+                get(recent_contacts_handler),
+            )
+            .route(
+                routes.add("GET", "/contacts/duplicates"),
+                get(duplicates_handler),
+            )
+            .route(
+                routes.add("GET", "/contacts/tag-summary"),
+                get(tag_summary_handler),
+            )
+            .route(
+                routes.add("GET", "/contacts/activity"),
+                get(contacts_activity_handler),
+            )
+            .route(
+                routes.add("GET", "/contacts/birthdays.ics"),
+                get(birthdays_calendar_handler),
+            )
+            .route(
+                routes.add("GET", "/contacts/print"),
+                get(print_contacts_handler),
+            )
+            .route(
+                routes.add("GET", "/contacts/export.json"),
+                get(export_contacts_handler),
+            )
+            .route(
+                routes.add("GET", "/contacts/delta"),
+                get(contacts_delta_handler),
+            )
+            .route(routes.add("POST", "/contact"), post(add_contact_handler))
+            .route(
+                routes.add("POST", "/contact.json"),
+                post(add_contact_json_handler),
+            )
+            .route(
+                routes.add("GET", "/contact/verify/{token}"),
+                get(verify_contact_handler),
+            )
+            .route(
+                routes.add("GET", "/contact/random"),
+                get(random_contact_handler),
+            )
+            .route(
+                routes.add("GET, POST, DELETE", "/contact/{id}"),
+                get(contact_handler)
+                    .post(add_contact_handler)
+                    .delete(delete_contact_handler),
+            )
+            .route(
+                routes.add("GET", "/contacts/more"),
+                get(more_contacts_handler),
+            )
+            .route(
+                routes.add("GET", "/contacts/load-more"),
+                get(load_more_contacts_handler),
+            )
+            .route(
+                routes.add("GET", "/contacts/search"),
+                get(search_contacts_handler),
+            )
+            .route(
+                routes.add("POST", "/contacts/bulk"),
+                post(bulk_contacts_handler),
+            )
+            .route(
+                routes.add("POST", "/contacts/tag"),
+                post(tag_contacts_handler),
+            )
+            .route(
+                routes.add("POST", "/contacts/compose"),
+                post(compose_email_handler),
+            )
+            .route(
+                routes.add("POST", "/contacts/import"),
+                post(import_contacts_handler),
+            )
+            .route(
+                routes.add("POST", "/contacts/import.json"),
+                post(import_json_contacts_handler),
+            )
+            .route(
+                routes.add("POST", "/contacts/merge"),
+                post(merge_contacts_handler),
+            )
+            .route(
+                routes.add("POST", "/contacts/reorder"),
+                post(reorder_contacts_handler),
+            )
+            .route(
+                routes.add("POST", "/contact/{id}/favorite"),
+                post(favorite_contact_handler),
+            )
+            .route(
+                routes.add("POST", "/contact/{id}/copy-email"),
+                post(copy_email_handler),
+            )
+            .route(
+                routes.add("GET", "/contact/{id}/edit-name"),
+                get(edit_name_handler),
+            )
+            .route(
+                routes.add("PUT", "/contact/{id}/name"),
+                put(update_name_handler),
+            )
+            .route(
+                routes.add("PUT", "/contact/{id}/email"),
+                put(update_email_handler),
+            )
+            .route(
+                routes.add("POST", "/contact/{id}/duplicate"),
+                post(duplicate_contact_handler),
+            )
+            .route(
+                routes.add("GET", "/contact/{id}/vcard.vcf"),
+                get(contact_vcard_handler),
+            )
+            .route(
+                routes.add("GET", "/contact/{id}/qr.svg"),
+                get(contact_qr_handler),
+            )
+            .with_state(contacts_app_state.clone())
+    } else {
+        Router::new()
+    };
 
+    // Sheds requests past the limit right away, a queue would only grow under a burst. The
+    // limit is global, Router::layer wraps every route on its own.
+    let overload_state = app_state.clone();
+    let limit_in_flight = |max_in_flight: usize| {
+        let state = overload_state.clone();
+        ServiceBuilder::new()
+            .layer(HandleErrorLayer::new(move |_: BoxError| {
+                overload::overloaded(state.clone())
+            }))
+            .load_shed()
+            .layer(GlobalConcurrencyLimitLayer::new(max_in_flight.max(1)))
+    };
+
+    // nest_service takes a prefix, so the listed path is registered separately
+    routes.add("GET", "/static/{*path}");
     let static_router = Router::new()
         .nest_service("/static", ServeDir::new("static"))
-        .route_service("/assets/main.css", ServeFile::new("assets/main.css"));
+        .route_service(
+            routes.add("GET", assets::STYLESHEET_URL),
+            ServeFile::new(assets::STYLESHEET_PATH),
+        );
+    let static_router = match stylesheet_url {
+        Some(url) => static_router.route_service(
+            routes.add("GET", url),
+            ServeFile::new(assets::STYLESHEET_PATH),
+        ),
+        None => static_router,
+    }
+    .layer(middleware::from_fn_with_state(
+        static_max_age,
+        cache_control::static_assets,
+    ))
+    .layer(limit_in_flight(max_static_in_flight));
+
+    // Also needs a working DNS setup, a run makes a DNS query for every email domain
+    let mx_check_router = if contacts_app_state.mx_checker.is_some() {
+        Router::new()
+            .route(
+                routes.add("POST", "/contacts/verify-emails"),
+                post(verify_emails_handler),
+            )
+            .with_state(contacts_app_state.clone())
+    } else {
+        Router::new()
+    };
 
-    let main_router: Router = Router::new()
+    // Probes for the orchestrator, merged after the trace layer to keep them out of the logs
+    let probe_router = Router::new()
+        .route(routes.add("GET", "/livez"), get(livez_handler))
+        .route(routes.add("GET", "/readyz"), get(readyz_handler))
+        .route(routes.add("GET", "/version"), get(version_handler))
+        .with_state(app_state.clone());
+
+    // Held open until the contacts change, so they're left out of the in-flight limit
+    let poll_router = if features.contacts {
+        Router::new()
+            .route(
+                routes.add("GET", "/contacts/poll"),
+                get(poll_contacts_handler),
+            )
+            .with_state(contacts_app_state.clone())
+    } else {
+        Router::new()
+    };
+
+    let version_state = contacts_app_state.clone();
+    // Debugging endpoints, only mounted in dev mode so they're never exposed in production
+    let admin_router = if features.admin {
+        let routes_path = routes.add("GET", "/admin/routes");
+        let state_path = routes.add("GET", "/admin/state");
+        let snapshot_path = routes.add("GET, POST", "/admin/snapshot");
+        let errors_path = routes.add("GET", "/admin/errors");
+        let cache_path = routes.add("GET", "/admin/cache");
+        let clear_path = routes.add("POST", "/admin/contacts/clear");
+        let admin_app_state = Arc::new(AdminAppState::new(
+            app_state,
+            counter_app_state,
+            contacts_app_state,
+            routes.into_routes(),
+        ));
+        Router::new()
+            .route(routes_path, get(admin_routes_handler))
+            .route(state_path, get(admin_state_handler))
+            .route(
+                snapshot_path,
+                get(export_snapshot_handler).post(import_snapshot_handler),
+            )
+            .route(errors_path, get(admin_errors_handler))
+            .route(cache_path, get(admin_cache_handler))
+            .route(clear_path, post(clear_contacts_handler))
+            .with_state(admin_app_state)
+    } else {
+        Router::new()
+    };
+
+    // Pages and fragments change with every edit, browsers have to revalidate them
+    let html_router = Router::new()
         .merge(root_router)
         .merge(counter_router)
-        .merge(contacts_router)
-        .merge(static_router)
-        .layer(TraceLayer::new_for_http());
+        .merge(refuse_changes(contacts_router))
+        .merge(refuse_changes(mx_check_router))
+        .merge(refuse_changes(admin_router))
+        .layer(limit_in_flight(max_in_flight))
+        .merge(poll_router)
+        .layer(middleware::from_fn_with_state(
+            version_state,
+            changes::announce_version,
+        ))
+        .layer(middleware::from_fn_with_state(
+            session_state,
+            session::require_login,
+        ))
+        .merge(login_router)
+        .fallback(not_found_handler)
+        .layer(middleware::from_fn(error::negotiate_format))
+        .layer(SetResponseHeaderLayer::if_not_present(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static("no-cache"),
+        ));
+
+    let sampler = Arc::new(ResponseSampler::new(log_sample_every));
+    let main_router: Router = Router::new().merge(html_router).merge(static_router).layer(
+        TraceLayer::new_for_http()
+            .make_span_with(|request: &Request<Body>| telemetry::make_request_span(request))
+            .on_response(move |response: &Response, latency: Duration, span: &Span| {
+                telemetry::record_status(response, span);
+                if sampler.should_log(response.status()) {
+                    DefaultOnResponse::default().on_response(response, latency, span);
+                }
+            }),
+    );
+    // Outside the trace layer, so the lines don't carry the request span's fields
+    let main_router = match access_log_config {
+        Some(config) => main_router.layer(middleware::from_fn_with_state(config, access_log::log)),
+        None => main_router,
+    }
+    .merge(probe_router)
+    .layer(middleware::from_fn_with_state(
+        trust_proxy,
+        request_id::assign,
+    ));
+    // Wrapped in a fallback so the path is rewritten before routing, a layer would run after it
+    let main_router = Router::new().fallback_service(
+        main_router
+            .map_request(trim_trailing_slash)
+            .map_request(client_ip::resolve(trust_proxy)),
+    );
 
-    // Create a socket
-    let port = 1337_u16;
-    let socket_addr = SocketAddr::from(([0, 0, 0, 0], port));
+    #[cfg(unix)]
+    if let Some(path) = unix_socket {
+        serve_unix_socket(&path, main_router, shutdown.clone()).await;
+    } else {
+        serve_tcp(socket_addr, main_router, shutdown.clone()).await;
+    }
+    #[cfg(not(unix))]
+    serve_tcp(socket_addr, main_router, shutdown.clone()).await;
+
+    // The server only returns once shutdown was requested, let the background tasks finish
+    cleanup_task.await.ok();
+    if let Some(mail_task) = mail_task {
+        mail_task.await.ok();
+    }
+    if let Some(backup_task) = backup_task {
+        backup_task.await.ok();
+    }
+    if let Some(flush_task) = flush_task {
+        flush_task.await.ok();
+    }
+    if let Some(tracer_provider) = tracer_provider {
+        if let Err(err) = tracer_provider.shutdown() {
+            warn!("Failed to flush traces: {err}");
+        }
+    }
+    info!("Server stopped");
+}
+
+// Templates are loaded from TEMPLATES_DIR on first use, with the filters, tests and globals
+// every page relies on
+fn template_env(
+    features: Features,
+    stylesheet_url: &'static str,
+    mx_check: bool,
+) -> Environment<'static> {
+    let mut tpl_env = Environment::new();
+    tpl_env.set_loader(path_loader(TEMPLATES_DIR));
+    // Block tags don't leave their line's indentation and newline in the output. Attributes
+    // in a conditional on a line of their own need a leading space.
+    tpl_env.set_trim_blocks(true);
+    tpl_env.set_lstrip_blocks(true);
+    // Only HTML gets escaped, values in text outputs like CSV or SVG are rendered as they are
+    tpl_env.set_auto_escape_callback(|name| {
+        match name.rsplit_once('.').map(|(_, extension)| extension) {
+            Some("html" | "htm") => AutoEscape::Html,
+            _ => AutoEscape::None,
+        }
+    });
+    // Seconds since the Unix epoch as date and time
+    tpl_env.add_filter("utc", activity::format_utc);
+    tpl_env.add_global("stylesheet_url", stylesheet_url);
+    // Shows the button for checking every contact's email domain
+    tpl_env.add_global("mx_check", mx_check);
+    // The sections linked from every page
+    tpl_env.add_global(
+        "features",
+        context! { counter => features.counter, contacts => features.contacts },
+    );
+    // `contact is favorite` and the like, for the contact's boolean flags
+    for flag in ["favorite", "archived", "verified", "undeliverable"] {
+        tpl_env.add_test(flag, move |value: &Value| {
+            value.get_attr(flag).is_ok_and(|field| field.is_true())
+        });
+    }
+    tpl_env
+}
+
+// Routes are registered without a trailing slash, so `/contacts/` is served like `/contacts`.
+// Paths under /static/ are left alone, ServeDir redirects directories to the slashed form.
+fn trim_trailing_slash(mut request: Request<Body>) -> Request<Body> {
+    let path = request.uri().path();
+    if path.len() <= 1 || !path.ends_with('/') || path.starts_with("/static/") {
+        return request;
+    }
+    let trimmed = &path[..path.len() - 1];
+    let path_and_query = match request.uri().query() {
+        Some(query) => format!("{trimmed}?{query}"),
+        None => trimmed.to_string(),
+    };
+    let mut parts = request.uri().clone().into_parts();
+    parts.path_and_query = path_and_query.parse().ok();
+    if let Ok(uri) = Uri::from_parts(parts) {
+        *request.uri_mut() = uri;
+    }
+    request
+}
 
-    // Start server
-    let listener = TcpListener::bind(socket_addr).await.unwrap();
+async fn serve_tcp(socket_addr: SocketAddr, router: Router, shutdown: CancellationToken) {
+    let listener = bind_tcp(socket_addr).unwrap();
     info!("Server running on {socket_addr}");
-    axum::serve(listener, main_router).await.unwrap();
+    // Makes the connecting address available as ConnectInfo, see `client_ip::resolve`
+    let service = router.into_make_service_with_connect_info::<SocketAddr>();
+    axum::serve(listener, service)
+        .with_graceful_shutdown(shutdown.cancelled_owned())
+        .await
+        .unwrap();
 }
 
-async fn index_handler(State(state): State<Arc<AppState>>) -> impl IntoResponse {
-    (
-        StatusCode::OK,
-        Html(render_block(&state, "base.html", &context! {}, "index")),
-    )
+// Binds the TCP listener, an IPv6 address also accepts IPv4 connections where the platform allows
+fn bind_tcp(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    use socket2::{Domain, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        if let Err(err) = socket.set_only_v6(false) {
+            warn!("Dual-stack not available, listening on IPv6 only: {err}");
+        }
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
 }
 
-async fn counter_handler(State(state): State<Arc<CounterAppState>>) -> impl IntoResponse {
-    let count = state.count.lock().await;
-    (
-        StatusCode::OK,
-        Html(render_block(
-            &state.app_state,
-            "counter.html",
-            &context! { count => *count },
-            "counter",
-        )),
+// Serves the app on a Unix domain socket, e.g. behind a reverse proxy on the same host
+#[cfg(unix)]
+async fn serve_unix_socket(path: &std::path::Path, router: Router, shutdown: CancellationToken) {
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    // A socket file left behind by a previous run would make bind fail
+    if path.exists() {
+        fs::remove_file(path).expect("Failed to remove stale socket file");
+    }
+    let listener = tokio::net::UnixListener::bind(path).unwrap();
+    // Owner and group (e.g. the proxy's group) may connect, others may not
+    fs::set_permissions(path, fs::Permissions::from_mode(0o660))
+        .expect("Failed to set socket permissions");
+
+    info!("Server running on unix socket {}", path.display());
+    axum::serve(listener, router)
+        .with_graceful_shutdown(shutdown.cancelled_owned())
+        .await
+        .unwrap();
+}
+
+async fn index_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    uri: Uri,
+) -> Result<impl IntoResponse, AppError> {
+    let mode = PageMode::from_headers(&headers);
+    let block = mode.block("index");
+    let body = render_cached_block(&state, "index", "base.html", &context! {}, block, "")?;
+    Ok((mode.headers(&uri), html_response(StatusCode::OK, body)))
+}
+
+// The plain /counter routes are an alias for the default counter
+async fn counter_handler(
+    State(state): State<Arc<CounterAppState>>,
+    headers: HeaderMap,
+    uri: Uri,
+) -> Response {
+    render_counter(
+        &state,
+        DEFAULT_COUNTER,
+        PageMode::from_headers(&headers),
+        &uri,
     )
+    .await
 }
 
-async fn increment_handler(State(state): State<Arc<CounterAppState>>) -> impl IntoResponse {
-    let mut count = state.count.lock().await;
-    *count += 1;
-    (
-        StatusCode::OK,
-        Html(render_block(
-            &state.app_state,
-            "counter.html",
-            &context! { count => *count },
-            "count",
-        )),
+async fn increment_handler(State(state): State<Arc<CounterAppState>>) -> Response {
+    update_counter(&state, DEFAULT_COUNTER, CounterChange::Increment).await
+}
+
+async fn decrement_handler(State(state): State<Arc<CounterAppState>>) -> Response {
+    update_counter(&state, DEFAULT_COUNTER, CounterChange::Decrement).await
+}
+
+async fn reset_handler(State(state): State<Arc<CounterAppState>>) -> Response {
+    update_counter(&state, DEFAULT_COUNTER, CounterChange::Reset).await
+}
+
+async fn named_counter_handler(
+    State(state): State<Arc<CounterAppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    uri: Uri,
+) -> Response {
+    if !is_valid_counter_name(&name) {
+        return not_found_handler().await.into_response();
+    }
+    render_counter(&state, &name, PageMode::from_headers(&headers), &uri).await
+}
+
+async fn named_increment_handler(
+    State(state): State<Arc<CounterAppState>>,
+    Path(name): Path<String>,
+) -> Response {
+    named_update(&state, &name, CounterChange::Increment).await
+}
+
+async fn named_decrement_handler(
+    State(state): State<Arc<CounterAppState>>,
+    Path(name): Path<String>,
+) -> Response {
+    named_update(&state, &name, CounterChange::Decrement).await
+}
+
+async fn named_reset_handler(
+    State(state): State<Arc<CounterAppState>>,
+    Path(name): Path<String>,
+) -> Response {
+    named_update(&state, &name, CounterChange::Reset).await
+}
+
+async fn counter_history_handler(
+    State(state): State<Arc<CounterAppState>>,
+    Query(query): Query<HistoryQuery>,
+) -> Response {
+    render_history(&state, DEFAULT_COUNTER, query.format).await
+}
+
+async fn named_counter_history_handler(
+    State(state): State<Arc<CounterAppState>>,
+    Path(name): Path<String>,
+    Query(query): Query<HistoryQuery>,
+) -> Response {
+    if !is_valid_counter_name(&name) {
+        return not_found_handler().await.into_response();
+    }
+    render_history(&state, &name, query.format).await
+}
+
+async fn render_history(state: &CounterAppState, name: &str, format: HistoryFormat) -> Response {
+    let points = state.history.lock().await.points(name);
+    match format {
+        HistoryFormat::Json => Json(json!({ "name": name, "points": points })).into_response(),
+        HistoryFormat::Svg => (
+            [(header::CONTENT_TYPE, "image/svg+xml")],
+            history::sparkline(&points),
+        )
+            .into_response(),
+    }
+}
+
+async fn named_update(state: &CounterAppState, name: &str, change: CounterChange) -> Response {
+    if !is_valid_counter_name(name) {
+        return not_found_handler().await.into_response();
+    }
+    update_counter(state, name, change).await
+}
+
+// Counters are created at the configured start value the first time they're shown
+async fn render_counter(
+    state: &CounterAppState,
+    name: &str,
+    mode: PageMode,
+    uri: &Uri,
+) -> Response {
+    let start = state.app_state.config.counter_start;
+    let count = *state
+        .counts
+        .lock()
+        .await
+        .entry(name.to_string())
+        .or_insert(start);
+    render_html(
+        &state.app_state,
+        "counter.html",
+        &context! { name => name, count => count },
+        mode.block("counter"),
     )
+    .map(|body| (mode.headers(uri), body))
+    .into_response()
 }
 
-async fn contacts_handler(State(state): State<Arc<ContactsAppState>>) -> impl IntoResponse {
-    let contacts = state.contacts.lock().await;
-    let reversed_contacts: Vec<_> = contacts.iter().rev().collect();
-    (
-        StatusCode::OK,
-        Html(render_block(
-            &state.app_state,
-            "contacts.html",
-            &context! { contacts => *reversed_contacts, formdata => FormRejectionData::new() },
-            "contacts",
-        )),
+enum CounterChange {
+    Increment,
+    // Stops at zero
+    Decrement,
+    // Back to the configured start value
+    Reset,
+}
+
+async fn update_counter(state: &CounterAppState, name: &str, change: CounterChange) -> Response {
+    let start = state.app_state.config.counter_start;
+    let mut counts = state.counts.lock().await;
+    let count = counts.entry(name.to_string()).or_insert(start);
+    *count = match change {
+        CounterChange::Increment => *count + 1,
+        CounterChange::Decrement => count.saturating_sub(1),
+        CounterChange::Reset => start,
+    };
+    let count = *count;
+    state.history.lock().await.push(name, count);
+    // Saved under the lock so the file never ends up with an older value than the last change
+    if let Some(counter_file) = &state.counter_file {
+        counter_file.save(&counts).await;
+    }
+    drop(counts);
+    state.app_state.mark_dirty();
+    render_html(
+        &state.app_state,
+        "counter.html",
+        &context! { name => name, count => count },
+        "count",
     )
+    // Lets the sparkline next to the counter refresh itself once the new count is shown
+    .map(|body| {
+        (
+            Triggers::new().after_swap("counter-changed", serde_json::Value::Null),
+            body,
+        )
+    })
+    .into_response()
 }
 
-async fn add_contact_handler(
+// Names end up in element ids and URLs, so they're kept to a safe set of characters
+fn is_valid_counter_name(name: &str) -> bool {
+    (1..=MAX_COUNTER_NAME_LENGTH).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+async fn contacts_handler(
     State(state): State<Arc<ContactsAppState>>,
-    Form(form): Form<FormData>,
-) -> impl IntoResponse {
-    let contacts = &mut state.contacts.lock().await;
-    let new_contact = Contact::new(&form.name, &form.email);
-    if !email_exists(&form.email, contacts) {
-        contacts.push(new_contact.clone());
-        let form_block = render_block(
+    Query(mut query): Query<ContactsQuery>,
+    headers: HeaderMap,
+    uri: Uri,
+) -> Response {
+    let search_cookie = remember_search(&mut query, &headers);
+    let sort_cookie = remember_sort(&mut query, &headers);
+    let contacts = state.contacts.lock().await;
+    let page = ContactListPage::new(&contacts, &query, &state.app_state.config);
+    // The status toggle swaps only the list and updates itself out of band. Restoring
+    // a pushed URL from history needs the whole page though, and a boosted link the
+    // page's content.
+    let mode = PageMode::from_headers(&headers);
+    let fragment = mode == PageMode::Full
+        && headers.contains_key("hx-request")
+        && !headers.contains_key("hx-history-restore-request");
+    let page_key = if fragment {
+        format!("fragment:{}", page.key())
+    } else if mode == PageMode::Boosted {
+        format!("boosted:{}", page.key())
+    } else {
+        page.key()
+    };
+
+    let etag = state.changes.etag(&page_key);
+    let mut response_headers = HeaderMap::new();
+    for cookie in [search_cookie, sort_cookie].into_iter().flatten() {
+        response_headers.append(header::SET_COOKIE, cookie);
+    }
+    if etag_matches(&headers, &etag) {
+        response_headers.insert(header::ETAG, etag.parse().unwrap());
+        return (StatusCode::NOT_MODIFIED, response_headers).into_response();
+    }
+
+    response_headers.insert(header::ETAG, etag.parse().unwrap());
+    response_headers.insert("x-total-count", page.pagination.total.into());
+    let links = if page.list_query.is_empty() {
+        page.pagination.link_header("/contacts")
+    } else {
+        page.pagination
+            .link_header(&format!("/contacts?{}", page.list_query))
+    };
+    if !links.is_empty() {
+        response_headers.insert(header::LINK, links.parse().unwrap());
+    }
+    // The page only depends on the contact list, which invalidates the cache on change
+    let body = if fragment {
+        let tpl_ctx = page.context(&contacts);
+        let list = render_cached_block(
             &state.app_state,
+            "contacts",
             "contacts.html",
-            &context! { formdata => FormRejectionData::new() },
-            "form",
+            &tpl_ctx,
+            "contactlist",
+            &page_key,
         );
-        let new_contact_block = render_block(
+        let toggle = try_render_block(
             &state.app_state,
             "contacts.html",
-            &context! { contact => new_contact },
-            "oob_contact",
-        );
-        return (
-            StatusCode::OK,
-            Html(form_block + new_contact_block.as_str()),
+            &context! { oob => true, ..tpl_ctx },
+            "status_toggle",
         );
+        list.and_then(|list| Ok(list + &toggle?))
+    } else {
+        render_cached_block(
+            &state.app_state,
+            "contacts",
+            "contacts.html",
+            &context! { version => state.changes.version(), ..page.context(&contacts) },
+            mode.block("contacts"),
+            &page_key,
+        )
+    };
+    let body = match body {
+        Ok(body) => body,
+        Err(err) => return AppError::from(err).into_response(),
+    };
+    response_headers.extend(mode.headers(&uri));
+    let focus = fragment.then(|| page_focus(&headers, &page)).flatten();
+    (StatusCode::OK, response_headers, focus, Html(body)).into_response()
+}
+
+// After following a page link the focus would be left on the swapped out link. It moves to
+// the first row of the new page instead, or to the page links on an empty page.
+fn page_focus(headers: &HeaderMap, page: &ContactListPage) -> Option<Triggers> {
+    // htmx sends the id of the element that made the request
+    let trigger = headers.get("hx-trigger")?.to_str().ok()?;
+    if !matches!(trigger, "page-prev" | "page-next") {
+        return None;
     }
+    let target = match page.contacts.first() {
+        Some(contact) => format!("contact-{}", contact.id),
+        None => "page-nav".to_string(),
+    };
+    Some(Triggers::new().after_settle("focus-field", json!({ "id": target })))
+}
 
-    let mut form_rejection_data = FormRejectionData::new();
-    form_rejection_data.set_value("name", &form.name);
-    form_rejection_data.set_value("email", &form.email);
-    form_rejection_data.set_error("email", "Email already exists");
+// A `q` parameter is remembered for the session, an empty one forgets it. Without one the
+// remembered query applies, so reloading /contacts keeps the search.
+fn remember_search(query: &mut ContactsQuery, headers: &HeaderMap) -> Option<HeaderValue> {
+    let cookie = match query.q.as_deref().map(str::trim) {
+        Some("") => cookies::clear(SEARCH_COOKIE),
+        Some(q) => cookies::set(SEARCH_COOKIE, q),
+        None => {
+            query.q = cookies::get(headers, SEARCH_COOKIE);
+            return None;
+        }
+    };
+    HeaderValue::from_str(&cookie).ok()
+}
+
+// Like the search, a `sort` parameter is remembered for the session and an empty one goes
+// back to newest first. Without one the remembered order applies.
+fn remember_sort(query: &mut ContactsQuery, headers: &HeaderMap) -> Option<HeaderValue> {
+    let cookie = match query.sort.as_deref().map(str::trim) {
+        Some("") => {
+            query.sort = None;
+            cookies::clear(SORT_COOKIE)
+        }
+        Some(sort) => cookies::set(SORT_COOKIE, sort),
+        None => {
+            query.sort = cookies::get(headers, SORT_COOKIE);
+            return None;
+        }
+    };
+    HeaderValue::from_str(&cookie).ok()
+}
 
-    let form_block = render_block(
+// The next batch of rows for infinite scrolling, newest first and older than the `after`
+// cursor. The last row fetches the batch after it once scrolled into view, an exhausted
+// list ends with an empty response.
+async fn more_contacts_handler(
+    State(state): State<Arc<ContactsAppState>>,
+    Query(query): Query<MoreQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let limit = query
+        .limit
+        .unwrap_or(state.app_state.config.contacts_per_page)
+        .clamp(1, MAX_PER_PAGE);
+    let contacts = state.contacts.lock().await;
+    let mut batch: Vec<&Contact> = contacts
+        .iter()
+        .filter(|contact| StatusFilter::Active.includes(contact))
+        .filter(|contact| query.after.is_none_or(|after| contact.id < after))
+        .collect();
+    batch.sort_by_key(|contact| std::cmp::Reverse(contact.id));
+    let has_more = batch.len() > limit;
+    batch.truncate(limit);
+
+    let last_id = batch.last().map(|contact| contact.id);
+    let rows = batch
+        .iter()
+        .map(|contact| {
+            let more_url =
+                (has_more && Some(contact.id) == last_id).then(|| more_url(contact.id, limit));
+            try_render_block(
+                &state.app_state,
+                "contact.html",
+                &context! { contact => contact, more_url => more_url },
+                "contact",
+            )
+        })
+        .collect::<Result<String, _>>()?;
+    Ok(html_response(StatusCode::OK, rows))
+}
+
+// The rows of the requested page for appending to the list, followed by the button for
+// the page after it, or by removing the button once the last page is shown
+async fn load_more_contacts_handler(
+    State(state): State<Arc<ContactsAppState>>,
+    Query(query): Query<ContactsQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let contacts = state.contacts.lock().await;
+    let page = ContactListPage::new(&contacts, &query, &state.app_state.config);
+    let tpl_ctx = page.context(&contacts);
+    let rows = try_render_block(
         &state.app_state,
         "contacts.html",
-        &context! { formdata => form_rejection_data },
-        "form",
-    );
+        &tpl_ctx,
+        "load_more_rows",
+    )?;
+    let button = try_render_block(
+        &state.app_state,
+        "load_more.html",
+        &context! { oob => true, ..tpl_ctx.clone() },
+        "load_more",
+    )?;
+    let meta = try_render_block(
+        &state.app_state,
+        "contacts.html",
+        &context! { oob => true, ..tpl_ctx },
+        "pagination",
+    )?;
+    Ok(html_response(StatusCode::OK, rows + &button + &meta))
+}
 
-    (StatusCode::UNPROCESSABLE_ENTITY, Html(form_block))
+// Search results as a fragment, best match first with fuzzy=true, newest first otherwise
+async fn search_contacts_handler(
+    State(state): State<Arc<ContactsAppState>>,
+    Query(query): Query<SearchQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let contacts = state.contacts.lock().await;
+    let q = query.q.trim();
+    let active = contacts
+        .iter()
+        .rev()
+        .filter(|contact| StatusFilter::Active.includes(contact));
+    let hits = if q.is_empty() {
+        Vec::new()
+    } else if query.fuzzy {
+        let config = &state.app_state.config;
+        search::fuzzy_search(
+            active,
+            q,
+            config.fuzzy_search_threshold,
+            config.fuzzy_search_limit,
+        )
+    } else {
+        active
+            .filter(|contact| search::matches(contact, q))
+            .map(|contact| SearchHit::new(contact, q))
+            .collect()
+    };
+    // Remembered like a search on the list, so going back to /contacts keeps it
+    let cookie = if q.is_empty() {
+        cookies::clear(SEARCH_COOKIE)
+    } else {
+        cookies::set(SEARCH_COOKIE, q)
+    };
+    render_html(
+        &state.app_state,
+        "contacts.html",
+        &context! { contacts => hits },
+        "search_results",
+    )
+    .map(|body| ([(header::SET_COOKIE, cookie)], body))
 }
 
-async fn delete_contact_handler() {}
+// Applies one action to every selected contact. Takes repeated `ids[]` fields, the `action`
+// and, for add-tag, the `tag`. The `list` field carries the query of the list being shown,
+// so the refreshed list comes back the way it was filtered, sorted and paginated.
+#[instrument(skip_all, fields(action, selected, affected, outcome))]
+async fn bulk_contacts_handler(
+    State(state): State<Arc<ContactsAppState>>,
+    FormFields(fields): FormFields,
+) -> Result<Html<String>, AppError> {
+    let action = match form_field(&fields, "action") {
+        Some("delete") => BulkAction::Delete,
+        Some("archive") => BulkAction::Archive,
+        Some("favorite") => BulkAction::Favorite,
+        Some("add-tag") => BulkAction::AddTag(bulk_tag(&state, form_field(&fields, "tag"))?),
+        action => {
+            Span::current().record("outcome", "invalid");
+            info!("Rejected unknown bulk action {action:?}");
+            return Err(AppError::Validation("Unknown bulk action".to_string()));
+        }
+    };
+    apply_bulk_action(&state, &fields, action).await
+}
 
-async fn not_found_handler() -> impl IntoResponse {
-    (StatusCode::NOT_FOUND, "This site does not exist :(")
+// Adds the `tag` to every selected contact that doesn't carry it yet, or with `remove=true`
+// strips it from every selected contact. Takes the same `ids[]` and `list` fields as the bulk
+// actions and answers the same way, with the refreshed list and the number of contacts changed.
+#[instrument(skip_all, fields(action, selected, affected, outcome))]
+async fn tag_contacts_handler(
+    State(state): State<Arc<ContactsAppState>>,
+    FormFields(fields): FormFields,
+) -> Result<Html<String>, AppError> {
+    let tag = bulk_tag(&state, form_field(&fields, "tag"))?;
+    let action = if form_field(&fields, "remove") == Some("true") {
+        BulkAction::RemoveTag(tag)
+    } else {
+        BulkAction::AddTag(tag)
+    };
+    apply_bulk_action(&state, &fields, action).await
 }
 
-fn email_exists(email: &str, contacts: &[Contact]) -> bool {
-    for contact in contacts.iter() {
-        if contact.email.eq(&email) {
-            return true;
-        }
+// The tag for an add or remove, rejected when empty or too long
+fn bulk_tag(state: &ContactsAppState, tag: Option<&str>) -> Result<String, AppError> {
+    let Some(tag) = tag.filter(|tag| !tag.is_empty()) else {
+        Span::current().record("outcome", "invalid");
+        return Err(AppError::Validation("A tag is needed".to_string()));
+    };
+    let tags = [tag.to_string()];
+    let input = ContactInput {
+        tags: Some(&tags),
+        ..Default::default()
+    };
+    if let Some(error) = validate_contact(&input, &state.app_state.config)
+        .errors
+        .remove("tags")
+    {
+        Span::current().record("outcome", "invalid");
+        return Err(AppError::Validation(error));
     }
-    false
+    let [tag] = tags;
+    Ok(tag)
 }
 
-#[derive(Serialize)]
-struct FormRejectionData {
-    values: HashMap<String, String>,
-    errors: HashMap<String, String>,
+// Applies the action to the selected contacts and renders the refreshed list along with the
+// summary of how many were changed
+async fn apply_bulk_action(
+    state: &ContactsAppState,
+    fields: &[(String, String)],
+    action: BulkAction,
+) -> Result<Html<String>, AppError> {
+    let ids = selected_ids(fields);
+    Span::current()
+        .record("action", action.label())
+        .record("selected", ids.len());
+
+    // One lock for the whole batch so it's applied all at once
+    let mut contacts = state.contacts.lock().await;
+    let affected = action.apply(&mut contacts, &ids, state.app_state.config.max_tags);
+    Span::current()
+        .record("affected", affected)
+        .record("outcome", "applied");
+    info!("Bulk action applied");
+    if affected > 0 {
+        let kind = match action {
+            BulkAction::Delete => ActivityKind::Deleted,
+            BulkAction::Archive => ActivityKind::Archived,
+            BulkAction::AddTag(_) | BulkAction::RemoveTag(_) | BulkAction::Favorite => {
+                ActivityKind::Updated
+            }
+        };
+        let plural = if affected == 1 { "" } else { "s" };
+        state.activity.record(
+            kind,
+            None,
+            format!("{affected} contact{plural} {}", action.label()),
+        );
+        state.contacts_changed(&contacts, ids);
+    }
+
+    let query: ContactsQuery = form_field(fields, "list")
+        .and_then(|list| serde_urlencoded::from_str(list).ok())
+        .unwrap_or_default();
+    let page = ContactListPage::new(&contacts, &query, &state.app_state.config);
+    let list = try_render_block(
+        &state.app_state,
+        "contacts.html",
+        &page.context(&contacts),
+        "contactlist",
+    )?;
+    let summary = try_render_trimmed_block(
+        &state.app_state,
+        "contacts.html",
+        &context! { affected => affected, action => action.label() },
+        "oob_bulk_summary",
+    )?;
+    let badge = try_render_trimmed_block(
+        &state.app_state,
+        "contacts.html",
+        &context! { favorites_count => count_favorites(&contacts) },
+        "oob_favorites_count",
+    )?;
+    Ok(Html(list + &summary + &badge))
 }
 
-impl FormRejectionData {
-    // Create a new form with empty values and errors
-    fn new() -> Self {
-        FormRejectionData {
-            values: HashMap::new(),
-            errors: HashMap::new(),
+// A mailto link for the selected contacts, taking the same `ids[]` fields as the bulk actions.
+// With `open` set the link is also opened right away through the open-mailto event.
+async fn compose_email_handler(
+    State(state): State<Arc<ContactsAppState>>,
+    FormFields(fields): FormFields,
+) -> Result<impl IntoResponse, AppError> {
+    let ids = selected_ids(&fields);
+    let contacts = state.contacts.lock().await;
+    let mut addresses: Vec<String> = Vec::new();
+    for contact in contacts.iter().filter(|contact| ids.contains(&contact.id)) {
+        if let Some(address) = normalize_email(&contact.email) {
+            if !addresses.contains(&address) {
+                addresses.push(address);
+            }
         }
     }
+    drop(contacts);
+    let mailto = (!addresses.is_empty()).then(|| mailto_bcc(&addresses));
+    let open = fields.iter().any(|(key, _)| key == "open");
+    let triggers = match &mailto {
+        Some(mailto) if open => Triggers::new().trigger("open-mailto", json!({ "url": mailto })),
+        _ => Triggers::new(),
+    };
+    let body = try_render_block(
+        &state.app_state,
+        "contacts.html",
+        &context! { mailto => mailto, recipients => addresses.len(), selected => ids.len() },
+        "compose",
+    )?;
+    Ok((triggers, Html(body)))
+}
 
-    fn set_value(&mut self, key: &str, value: &str) {
-        self.values.insert(key.into(), value.into());
+// Marks contacts whose email domain has no MX record as undeliverable and clears the mark
+// where one turned up. Contacts whose lookup failed keep their mark.
+#[instrument(skip_all, fields(domains, undeliverable, failed, outcome))]
+async fn verify_emails_handler(
+    State(state): State<Arc<ContactsAppState>>,
+) -> Result<Html<String>, AppError> {
+    let Some(mx_checker) = &state.mx_checker else {
+        return Err(not_found_handler().await);
+    };
+    if let Err(retry_after) = mx_checker.start_run().await {
+        Span::current().record("outcome", "rate_limited");
+        let body = try_render_block(
+            &state.app_state,
+            "contacts.html",
+            &context! { retry_after => retry_after.as_secs().max(1) },
+            "mx_summary",
+        )?;
+        return Err(AppError::RateLimited { body, retry_after });
     }
+    let domain = |contact: &Contact| {
+        let email = normalize_email(&contact.email)?;
+        email_domain(&email).map(str::to_string)
+    };
+    // Not locked during the lookups, contacts added in the meantime are left for the next run
+    let domains: HashSet<String> = state
+        .contacts
+        .lock()
+        .await
+        .iter()
+        .filter_map(domain)
+        .collect();
+    let checked = domains.len();
+    let statuses = mx_checker.check(domains).await;
+    let failed = statuses
+        .values()
+        .filter(|status| **status == MxStatus::Failed)
+        .count();
 
-    fn set_error(&mut self, key: &str, error: &str) {
-        self.errors.insert(key.into(), error.into());
+    let mut contacts = state.contacts.lock().await;
+    let mut changed_ids = Vec::new();
+    for contact in contacts.iter_mut() {
+        let undeliverable = match domain(contact).and_then(|domain| statuses.get(&domain)) {
+            Some(MxStatus::Found) => false,
+            Some(MxStatus::Missing) => true,
+            Some(MxStatus::Failed) | None => continue,
+        };
+        if contact.undeliverable != undeliverable {
+            contact.undeliverable = undeliverable;
+            changed_ids.push(contact.id);
+        }
+    }
+    let undeliverable = contacts
+        .iter()
+        .filter(|contact| contact.undeliverable)
+        .count();
+    let changed = changed_ids.len();
+    if changed > 0 {
+        state.contacts_changed(&contacts, changed_ids);
+    }
+    drop(contacts);
+    Span::current()
+        .record("domains", checked)
+        .record("undeliverable", undeliverable)
+        .record("failed", failed)
+        .record("outcome", "checked");
+    info!("Email domains checked");
+    if changed > 0 {
+        let plural = if changed == 1 { "" } else { "s" };
+        state.activity.record(
+            ActivityKind::Updated,
+            None,
+            format!("Deliverability of {changed} contact{plural} changed"),
+        );
     }
+    render_html(
+        &state.app_state,
+        "contacts.html",
+        &context! { checked => checked, undeliverable => undeliverable, failed => failed },
+        "mx_summary",
+    )
 }
 
-impl Default for FormRejectionData {
-    fn default() -> Self {
-        Self::new()
-    }
+// The first value of a field of the bulk form, trimmed
+fn form_field<'a>(fields: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    fields
+        .iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.trim())
 }
 
-#[derive(Deserialize)]
-struct FormData {
-    name: String,
-    email: String,
+// Ids from the repeated `ids[]` fields of the bulk form, anything that isn't an id is skipped
+fn selected_ids(fields: &[(String, String)]) -> Vec<usize> {
+    fields
+        .iter()
+        .filter(|(key, _)| key == "ids[]" || key == "ids")
+        .filter_map(|(_, value)| value.trim().parse().ok())
+        .collect()
 }
 
-struct ContactsAppState {
-    app_state: Arc<AppState>,
-    contacts: Mutex<Vec<Contact>>,
+// The detail page, also moves the contact to the front of the recently viewed ones
+async fn contact_handler(
+    State(state): State<Arc<ContactsAppState>>,
+    Path(id): Path<usize>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let contacts = state.contacts.lock().await;
+    let Some(contact) = contacts.iter().find(|contact| contact.id == id) else {
+        return Err(AppError::NotFound("Contact does not exist".to_string()));
+    };
+    let mut recent = recent_contact_ids(&headers);
+    recent.retain(|recent_id| *recent_id != id);
+    recent.insert(0, id);
+    recent.truncate(MAX_RECENT_CONTACTS);
+    let recent = recent
+        .iter()
+        .map(usize::to_string)
+        .collect::<Vec<_>>()
+        .join(",");
+    let body = try_render_block(
+        &state.app_state,
+        "contact_detail.html",
+        &context! { contact => contact },
+        "contact_detail",
+    )?;
+    Ok((
+        [(header::SET_COOKIE, cookies::set(RECENT_COOKIE, &recent))],
+        Html(body),
+    )
+        .into_response())
 }
 
-#[derive(Clone, Serialize)]
-struct Contact {
-    name: String,
-    email: String,
-    id: usize,
+// A random active contact's details as a fragment, for a "reconnect with someone" prompt
+async fn random_contact_handler(
+    State(state): State<Arc<ContactsAppState>>,
+) -> Result<Html<String>, AppError> {
+    let contacts = state.contacts.lock().await;
+    let active: Vec<_> = contacts
+        .iter()
+        .filter(|contact| StatusFilter::Active.includes(contact))
+        .collect();
+    let contact = active.choose(&mut rand::rng());
+    render_html(
+        &state.app_state,
+        "contact_detail.html",
+        &context! { contact => contact, random => true },
+        "contact_card",
+    )
 }
 
-struct CounterAppState {
-    app_state: Arc<AppState>,
-    count: Mutex<usize>,
+// The contacts last opened in this session, most recent first. Deleted ones are left out.
+async fn recent_contacts_handler(
+    State(state): State<Arc<ContactsAppState>>,
+    headers: HeaderMap,
+) -> Result<Html<String>, AppError> {
+    let contacts = state.contacts.lock().await;
+    let recent: Vec<_> = recent_contact_ids(&headers)
+        .into_iter()
+        .filter_map(|id| contacts.iter().find(|contact| contact.id == id))
+        .collect();
+    render_html(
+        &state.app_state,
+        "contacts.html",
+        &context! { recent => recent },
+        "recent_contacts",
+    )
 }
 
-struct AppState {
-    tpl_env: Environment<'static>,
+// Ids from the session cookie, anything that isn't an id is skipped
+fn recent_contact_ids(headers: &HeaderMap) -> Vec<usize> {
+    cookies::get(headers, RECENT_COOKIE)
+        .map(|ids| ids.split(',').filter_map(|id| id.parse().ok()).collect())
+        .unwrap_or_default()
 }
 
-static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
-impl Contact {
-    fn new(name: &str, email: &str) -> Self {
-        Self {
-            name: name.to_string(),
-            email: email.to_string(),
-            id: NEXT_ID.fetch_add(1, SeqCst),
-        }
-    }
+// Groups of contacts that look like the same person, each with a form for merging them
+// into the one picked as primary. Only suggests, nothing changes until a merge is posted.
+async fn duplicates_handler(
+    State(state): State<Arc<ContactsAppState>>,
+) -> Result<Html<String>, AppError> {
+    let contacts = state.contacts.lock().await;
+    let groups: Vec<_> =
+        duplicates::find(&contacts, state.app_state.config.duplicate_name_similarity)
+            .into_iter()
+            .map(|group| context! { contacts => group.contacts, same_email => group.same_email })
+            .collect();
+    render_html(
+        &state.app_state,
+        "contacts.html",
+        &context! { groups => groups },
+        "duplicates",
+    )
 }
 
-impl ContactsAppState {
-    fn new(app_state: Arc<AppState>, contacts: Vec<Contact>) -> Self {
-        Self {
-            app_state,
-            contacts: Mutex::new(contacts),
+// Long poll for clients that can't keep a stream open. Answers once the contacts changed from
+// the `since` version with the list as given in `list`, like the bulk form sends it, and a
+// poll for the next change. Without a change before the timeout htmx gets the poll back to
+// try again, other clients a 204. The version is in X-Contacts-Version either way.
+async fn poll_contacts_handler(
+    State(state): State<Arc<ContactsAppState>>,
+    Query(poll): Query<PollQuery>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let version = state
+        .changes
+        .wait_for_change(poll.since, state.app_state.config.long_poll_timeout)
+        .await;
+    let version_header = [("x-contacts-version", version.to_string())];
+    let poller = try_render_block(
+        &state.app_state,
+        "contacts.html",
+        &context! { version },
+        "contacts_poll",
+    )?;
+    if version == poll.since {
+        if !headers.contains_key("hx-request") {
+            return Ok((StatusCode::NO_CONTENT, version_header).into_response());
         }
+        return Ok((version_header, Html(poller)).into_response());
     }
+
+    let contacts = state.contacts.lock().await;
+    let query: ContactsQuery = serde_urlencoded::from_str(&poll.list).unwrap_or_default();
+    let page = ContactListPage::new(&contacts, &query, &state.app_state.config);
+    let tpl_ctx = context! { oob_list => true, oob => true, ..page.context(&contacts) };
+    let list = try_render_block(&state.app_state, "contacts.html", &tpl_ctx, "contactlist")?;
+    let toggle = try_render_block(&state.app_state, "contacts.html", &tpl_ctx, "status_toggle")?;
+    Ok((version_header, Html(poller + &list + &toggle)).into_response())
 }
 
-impl CounterAppState {
-    fn new(app_state: Arc<AppState>, count: usize) -> Self {
-        Self {
-            app_state,
-            count: Mutex::new(count),
+// For clients keeping their own copy of the list: the contacts created or changed after the
+// `since_version` they have, each with the version it last changed at, and the ids of the
+// ones deleted since. With `full` set the version wasn't known, or none was given, and every
+// contact is listed, the client should replace its copy. It moves on with `version`.
+async fn contacts_delta_handler(
+    State(state): State<Arc<ContactsAppState>>,
+    Query(query): Query<DeltaQuery>,
+) -> impl IntoResponse {
+    #[derive(Serialize)]
+    struct VersionedContact<'a> {
+        #[serde(flatten)]
+        contact: &'a Contact,
+        version: u64,
+    }
+
+    let contacts = state.contacts.lock().await;
+    let delta = state.changes.delta(query.since_version);
+    let changed: Vec<_> = contacts
+        .iter()
+        .filter_map(|contact| {
+            let version = *delta.changed.get(&contact.id)?;
+            Some(VersionedContact { contact, version })
+        })
+        .collect();
+    Json(json!({
+        "version": delta.version,
+        "full": delta.full,
+        "contacts": changed,
+        "deleted": delta.deleted,
+    }))
+}
+
+// A chip per tag with the number of contacts carrying it, each filtering the list by its
+// tag. Counts the contacts the list shows by default, tags only on archived ones are left
+// out, and tags differing only in case count as one like in the filter.
+async fn tag_summary_handler(
+    State(state): State<Arc<ContactsAppState>>,
+) -> Result<Html<String>, AppError> {
+    let contacts = state.contacts.lock().await;
+    // Keyed by the lowercased tag, showing the spelling seen first
+    let mut counts: HashMap<String, (&str, usize)> = HashMap::new();
+    for contact in contacts
+        .iter()
+        .filter(|contact| StatusFilter::Active.includes(contact))
+    {
+        let mut seen = HashSet::new();
+        for tag in &contact.tags {
+            let key = tag.to_lowercase();
+            if seen.insert(key.clone()) {
+                counts.entry(key).or_insert((tag, 0)).1 += 1;
+            }
+        }
+    }
+    let mut tags: Vec<_> = counts.into_values().collect();
+    tags.sort_by(|(a, a_count), (b, b_count)| {
+        b_count
+            .cmp(a_count)
+            .then_with(|| a.to_lowercase().cmp(&b.to_lowercase()))
+    });
+    let tags: Vec<_> = tags
+        .into_iter()
+        .map(|(tag, count)| {
+            let query = serde_urlencoded::to_string([("tag", tag)]).unwrap_or_default();
+            context! { tag, count, query }
+        })
+        .collect();
+    render_html(
+        &state.app_state,
+        "contacts.html",
+        &context! { tags => tags },
+        "tag_summary",
+    )
+}
+
+// Recent changes as a fragment, newest first and paginated like the contact list
+async fn contacts_activity_handler(
+    State(state): State<Arc<ContactsAppState>>,
+    Query(query): Query<ActivityQuery>,
+) -> Result<Html<String>, AppError> {
+    let mut events = state.activity.recent();
+    let per_page = query
+        .per_page
+        .unwrap_or(state.app_state.config.contacts_per_page)
+        .min(MAX_PER_PAGE);
+    let pagination = Pagination::paginate(&mut events, query.page.unwrap_or(1), per_page);
+    render_html(
+        &state.app_state,
+        "activity.html",
+        &context! { events => events, pagination => pagination },
+        "activity",
+    )
+}
+
+// Birthdays as an iCalendar feed that calendar apps can subscribe to, a yearly all-day
+// event for every contact with a birthday that isn't archived
+async fn birthdays_calendar_handler(
+    State(state): State<Arc<ContactsAppState>>,
+) -> impl IntoResponse {
+    let contacts = state.contacts.lock().await;
+    (
+        [(header::CONTENT_TYPE, "text/calendar; charset=utf-8")],
+        birthday::calendar(&contacts),
+    )
+}
+
+async fn contact_vcard_handler(
+    State(state): State<Arc<ContactsAppState>>,
+    Path(id): Path<usize>,
+) -> Result<Response, AppError> {
+    let contacts = state.contacts.lock().await;
+    let Some(contact) = contacts.iter().find(|contact| contact.id == id) else {
+        return Err(AppError::NotFound("Contact does not exist".to_string()));
+    };
+    Ok((
+        [
+            (
+                header::CONTENT_TYPE,
+                "text/vcard; charset=utf-8".to_string(),
+            ),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"contact-{id}.vcf\""),
+            ),
+        ],
+        vcard::card(contact, true),
+    )
+        .into_response())
+}
+
+async fn contact_qr_handler(
+    State(state): State<Arc<ContactsAppState>>,
+    Path(id): Path<usize>,
+) -> Result<Response, AppError> {
+    let contacts = state.contacts.lock().await;
+    let Some(contact) = contacts.iter().find(|contact| contact.id == id) else {
+        return Err(AppError::NotFound("Contact does not exist".to_string()));
+    };
+    let svg = vcard::qr_svg(contact).map_err(|err| {
+        warn!(
+            contact_id = id,
+            "Failed to encode the vCard as a QR code: {err}"
+        );
+        AppError::Validation("The contact is too long for a QR code".to_string())
+    })?;
+    Ok(([(header::CONTENT_TYPE, "image/svg+xml")], svg).into_response())
+}
+
+// Every contact the list shows by default on one page for printing, sorted by name. Always
+// the full page, a fragment of it is no use for printing.
+async fn print_contacts_handler(
+    State(state): State<Arc<ContactsAppState>>,
+) -> Result<Html<String>, AppError> {
+    let contacts = state.contacts.lock().await;
+    let mut shown: Vec<&Contact> = contacts
+        .iter()
+        .filter(|contact| StatusFilter::Active.includes(contact))
+        .collect();
+    ContactSorter::parse("name").sort(&mut shown);
+    render_html(
+        &state.app_state,
+        "print.html",
+        &context! { contacts => shown },
+        "print",
+    )
+}
+
+// All contacts as a pretty-printed JSON download, oldest first like the data file
+async fn export_contacts_handler(State(state): State<Arc<ContactsAppState>>) -> impl IntoResponse {
+    #[derive(Serialize)]
+    struct Export<'a> {
+        // Seconds since the Unix epoch
+        exported_at: u64,
+        contacts: &'a [Contact],
+    }
+
+    let contacts = state.contacts.lock().await;
+    let exported_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default();
+    let body = serde_json::to_string_pretty(&Export {
+        exported_at,
+        contacts: &contacts,
+    })
+    .expect("Contacts always serialize");
+    (
+        [
+            (header::CONTENT_TYPE, "application/json"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"contacts.json\"",
+            ),
+        ],
+        body,
+    )
+}
+
+// Streams the contact rows one fragment at a time instead of buffering the whole list
+async fn contacts_stream_handler(State(state): State<Arc<ContactsAppState>>) -> impl IntoResponse {
+    // Take a snapshot so the lock isn't held while the rows are rendered
+    let contacts: Vec<Contact> = state.contacts.lock().await.iter().rev().cloned().collect();
+    let rows = stream::iter(contacts).map(move |contact| {
+        try_render_trimmed_block(
+            &state.app_state,
+            "contact.html",
+            &context! { contact => contact },
+            "contact",
+        )
+        .map_err(|err| {
+            // Ending the stream with an error makes hyper close the connection
+            error!("Failed to render contact {}: {err}", contact.id);
+            std::io::Error::other(err)
+        })
+    });
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "text/html; charset=utf-8")],
+        Body::from_stream(rows),
+    )
+}
+
+// The fields of a new contact once checked, as they're stored
+struct CheckedContact {
+    name: String,
+    email: String,
+    notes: String,
+    phone: String,
+    // Not recognized as a phone number and kept as entered, warned about but not rejected
+    phone_unparsed: bool,
+    birthday: String,
+}
+
+// A contact's fields as submitted, None for the ones a request doesn't set
+#[derive(Default)]
+struct ContactInput<'a> {
+    name: Option<&'a str>,
+    email: Option<&'a str>,
+    phone: Option<&'a str>,
+    notes: Option<&'a str>,
+    tags: Option<&'a [String]>,
+}
+
+// The constraints on a contact's fields, keyed by field in the errors. Adding, editing and
+// importing contacts all check here, so the form and the JSON API reject the same input with
+// the same messages. Formats like phone numbers and birthdays are checked where they're parsed.
+fn validate_contact(input: &ContactInput, config: &Config) -> FormRejectionData {
+    let mut rejection = FormRejectionData::new();
+    for (field, label, value) in [
+        ("name", "Name", input.name),
+        ("email", "Email", input.email),
+    ] {
+        if value.is_some_and(|value| value.trim().is_empty()) {
+            rejection.set_error(field, &format!("{label} is required"));
+        }
+    }
+    let email = input.email.map(str::trim).filter(|email| !email.is_empty());
+    if email.is_some_and(|email| !is_email_address(email)) {
+        rejection.set_error("email", "Not a valid email address");
+    }
+    for (field, value, max_length) in [
+        ("name", input.name, config.max_name_length),
+        ("email", input.email, config.max_email_length),
+        ("phone", input.phone, config.max_phone_length),
+        ("notes", input.notes, config.max_notes_length),
+    ] {
+        if value.is_some_and(|value| value.chars().count() > max_length) {
+            rejection.set_error(field, &format!("At most {max_length} characters"));
+        }
+    }
+    if let Some(tags) = input.tags {
+        let max_tag_length = config.max_tag_length;
+        if tags.len() > config.max_tags {
+            rejection.set_error("tags", &format!("At most {} tags", config.max_tags));
+        } else if tags.iter().any(|tag| tag.chars().count() > max_tag_length) {
+            rejection.set_error(
+                "tags",
+                &format!("Tags of at most {max_tag_length} characters"),
+            );
+        }
+    }
+    rejection
+}
+
+// The phone number in E.164 form with PHONE_DEFAULT_COUNTRY set, and whether it had to be kept
+// as entered because it isn't recognizable as one. A number that can't exist is an error.
+fn check_phone(phone: &str, config: &Config, errors: &mut FormRejectionData) -> (String, bool) {
+    let phone = phone.trim();
+    let Some(country) = config.phone_default_country.filter(|_| !phone.is_empty()) else {
+        return (phone.to_string(), false);
+    };
+    match phone::normalize(phone, country) {
+        PhoneCheck::Normalized(normalized) => (normalized, false),
+        PhoneCheck::Unparsed => (phone.to_string(), true),
+        PhoneCheck::Invalid => {
+            errors.set_error("phone", "Not a valid phone number");
+            (phone.to_string(), false)
+        }
+    }
+}
+
+// The first problem in the order of the fields as `field: error`, for reporting a rejected
+// entry of an import in one line
+fn first_error(rejection: &mut FormRejectionData) -> Option<String> {
+    ["name", "email", "phone", "notes", "tags"]
+        .into_iter()
+        .find_map(|field| Some(format!("{field}: {}", rejection.errors.remove(field)?)))
+}
+
+// Checks a new contact's fields and records what's wrong with them in `errors`, keyed by
+// field. The form and the JSON API both go through here, so they reject the same input.
+fn check_new_contact(
+    form: &FormData,
+    config: &Config,
+    errors: &mut FormRejectionData,
+) -> CheckedContact {
+    let input = ContactInput {
+        name: Some(&form.name),
+        email: Some(&form.email),
+        phone: Some(&form.phone),
+        notes: Some(&form.notes),
+        tags: None,
+    };
+    errors
+        .errors
+        .extend(validate_contact(&input, config).errors);
+    let (phone, phone_unparsed) = check_phone(&form.phone, config, errors);
+
+    let birthday = match form.birthday.trim() {
+        "" => String::new(),
+        raw => Birthday::parse(raw)
+            .map(|b| b.to_string())
+            .unwrap_or_else(|| {
+                errors.set_error("birthday", "Not a date, use YYYY-MM-DD or --MM-DD");
+                String::new()
+            }),
+    };
+    CheckedContact {
+        name: form.name.trim().to_string(),
+        email: form.email.trim().to_string(),
+        notes: form.notes.trim().to_string(),
+        phone,
+        phone_unparsed,
+        birthday,
+    }
+}
+
+#[instrument(
+    skip_all,
+    fields(email_domain = redact_email(&form.email), contact_id, outcome)
+)]
+async fn add_contact_handler(
+    State(state): State<Arc<ContactsAppState>>,
+    headers: HeaderMap,
+    Form(form): Form<FormData>,
+) -> Result<Response, AppError> {
+    let config = &state.app_state.config;
+    // Without a target the form replaces itself, see below for the other contexts
+    let target = hx_target(&headers);
+    let mut form_rejection_data = FormRejectionData::new();
+    let checked = check_new_contact(&form, config, &mut form_rejection_data);
+
+    let contacts = &mut state.contacts.lock().await;
+    let conflict = form_rejection_data.errors.is_empty() && email_exists(&form.email, contacts);
+    if conflict {
+        form_rejection_data.set_error("email", "Email already exists");
+    }
+    if form_rejection_data.errors.is_empty() {
+        // Warnings don't block the contact, they're shown on the fresh form instead
+        let mut form_data = FormRejectionData::new();
+        if name_exists(&form.name, contacts) {
+            form_data.set_warning("name", "A contact with a similar name already exists");
+        }
+        if checked.phone_unparsed {
+            warn!("Phone number not recognized, saved as entered");
+            form_data.set_warning(
+                "phone",
+                "Not recognized as a phone number, saved as entered",
+            );
+        }
+
+        let new_contact = insert_contact(&state, contacts, checked).await;
+        // A form that swaps the whole list gets the first page with the new contact on top,
+        // the form clears itself
+        if target.as_deref() == Some("contactlist-page") {
+            let query = ContactsQuery::default();
+            let page = ContactListPage::new(contacts, &query, config);
+            let list = render_html(
+                &state.app_state,
+                "contacts.html",
+                &page.context(contacts),
+                "contactlist",
+            )?;
+            return Ok(list.into_response());
+        }
+        // A fresh form clears the inputs for the next contact
+        let form_block = render_form(&state.app_state, &form_data)?;
+        let new_contact_block = try_render_trimmed_block(
+            &state.app_state,
+            "contacts.html",
+            &context! { contact => new_contact },
+            "oob_contact",
+        )?;
+        // Ready for typing the next contact
+        let focus = Triggers::new().after_settle("focus-field", json!({ "id": "name" }));
+        return Ok((focus, Html(form_block + new_contact_block.as_str())).into_response());
+    }
+
+    form_rejection_data.set_values(&form);
+    if conflict {
+        Span::current().record("outcome", "conflict");
+        info!("Contact rejected, email already exists");
+    } else {
+        Span::current().record("outcome", "invalid");
+        info!("Contact rejected, invalid fields");
+    }
+
+    // A form with its own error container keeps its inputs and only gets the messages
+    let form_block = if target.as_deref() == Some("form-errors") {
+        try_render_block(
+            &state.app_state,
+            "contacts.html",
+            &context! { formdata => form_rejection_data },
+            "form_errors",
+        )?
+    } else {
+        render_form(&state.app_state, &form_rejection_data)?
+    };
+    let error = if conflict {
+        AppError::Conflict(form_block)
+    } else {
+        AppError::Validation(form_block)
+    };
+    // The first field to fix, in the order of the form
+    let invalid = ["name", "email", "phone", "notes", "birthday"]
+        .into_iter()
+        .find(|field| form_rejection_data.errors.contains_key(*field));
+    let focus = Triggers::new().after_settle("focus-field", json!({ "id": invalid }));
+    Ok((focus, error).into_response())
+}
+
+// Adds a contact whose fields passed check_new_contact, shared by the form and the JSON API
+async fn insert_contact(
+    state: &ContactsAppState,
+    contacts: &mut Vec<Contact>,
+    checked: CheckedContact,
+) -> Contact {
+    let mut new_contact = Contact::new(&checked.name, &checked.email);
+    new_contact.phone = checked.phone;
+    new_contact.notes = checked.notes;
+    new_contact.birthday = checked.birthday;
+    require_verification(state, &mut new_contact).await;
+    contacts.push(new_contact.clone());
+    Span::current()
+        .record("contact_id", new_contact.id)
+        .record("outcome", "created");
+    info!("Contact created");
+    state.activity.record(
+        ActivityKind::Created,
+        Some(new_contact.id),
+        format!("Added {}", new_contact.name),
+    );
+    state.contacts_changed(contacts, [new_contact.id]);
+    new_contact
+}
+
+// The JSON variant of POST /contact, answers with the new contact and 201. Rejections carry
+// the same messages as the form, keyed by field: `{"errors": {"email": "..."}}` with 422,
+// or 409 when the email already exists. A body that isn't a contact gets 400.
+#[instrument(skip_all, fields(email_domain, contact_id, outcome))]
+async fn add_contact_json_handler(
+    State(state): State<Arc<ContactsAppState>>,
+    body: Result<Json<FormData>, JsonRejection>,
+) -> Response {
+    let form = match body {
+        Ok(Json(form)) => form,
+        Err(rejection) => {
+            Span::current().record("outcome", "invalid");
+            let errors = json!({ "errors": { "body": rejection.body_text() } });
+            return (StatusCode::BAD_REQUEST, Json(errors)).into_response();
+        }
+    };
+    Span::current().record("email_domain", redact_email(&form.email));
+    let config = &state.app_state.config;
+    let mut form_rejection_data = FormRejectionData::new();
+    let checked = check_new_contact(&form, config, &mut form_rejection_data);
+
+    let mut contacts = state.contacts.lock().await;
+    let conflict = form_rejection_data.errors.is_empty() && email_exists(&form.email, &contacts);
+    if conflict {
+        form_rejection_data.set_error("email", "Email already exists");
+    }
+    if !form_rejection_data.errors.is_empty() {
+        let (status, outcome) = if conflict {
+            (StatusCode::CONFLICT, "conflict")
+        } else {
+            (StatusCode::UNPROCESSABLE_ENTITY, "invalid")
+        };
+        Span::current().record("outcome", outcome);
+        info!("Contact rejected");
+        let errors = json!({ "errors": form_rejection_data.errors });
+        return (status, Json(errors)).into_response();
+    }
+    let new_contact = insert_contact(&state, &mut contacts, checked).await;
+    let location = format!("/contact/{}", new_contact.id);
+    (
+        StatusCode::CREATED,
+        [(header::LOCATION, location)],
+        Json(new_contact),
+    )
+        .into_response()
+}
+
+// Nothing to swap, the copy-email event carries the email to the listener on the page
+async fn copy_email_handler(
+    State(state): State<Arc<ContactsAppState>>,
+    Path(id): Path<usize>,
+) -> Result<impl IntoResponse, AppError> {
+    let contacts = state.contacts.lock().await;
+    let Some(contact) = contacts.iter().find(|contact| contact.id == id) else {
+        return Err(AppError::NotFound("Contact does not exist".to_string()));
+    };
+    let triggers = Triggers::new().trigger("copy-email", json!({ "email": contact.email }));
+    Ok((StatusCode::NO_CONTENT, triggers))
+}
+
+async fn edit_name_handler(
+    State(state): State<Arc<ContactsAppState>>,
+    Path(id): Path<usize>,
+) -> Result<Html<String>, AppError> {
+    let contacts = state.contacts.lock().await;
+    let Some(contact) = contacts.iter().find(|contact| contact.id == id) else {
+        return Err(AppError::NotFound("Contact does not exist".to_string()));
+    };
+    render_html(
+        &state.app_state,
+        "contact.html",
+        &context! { contact => contact, edit_name => true, name => contact.name },
+        "name_cell",
+    )
+}
+
+#[instrument(skip_all, fields(contact_id = id, outcome))]
+async fn update_name_handler(
+    State(state): State<Arc<ContactsAppState>>,
+    Path(id): Path<usize>,
+    Form(form): Form<NameForm>,
+) -> Result<Html<String>, AppError> {
+    let mut contacts = state.contacts.lock().await;
+    let Some(contact) = contacts.iter_mut().find(|contact| contact.id == id) else {
+        info!("Contact to rename does not exist");
+        return Err(AppError::NotFound("Contact does not exist".to_string()));
+    };
+    let input = ContactInput {
+        name: Some(&form.name),
+        ..Default::default()
+    };
+    if let Some(error) = validate_contact(&input, &state.app_state.config)
+        .errors
+        .remove("name")
+    {
+        Span::current().record("outcome", "invalid");
+        info!("Contact name rejected");
+        // The input keeps what was typed so it can be corrected
+        return Err(AppError::Validation(try_render_block(
+            &state.app_state,
+            "contact.html",
+            &context! { contact => contact, edit_name => true, name => form.name, error => error },
+            "name_cell",
+        )?));
+    }
+    let old_name = std::mem::replace(&mut contact.name, form.name.trim().to_string());
+    field_history::record(
+        &mut contact.history,
+        state.app_state.config.field_history_size,
+        "name",
+        &old_name,
+        &contact.name,
+    );
+    Span::current().record("outcome", "renamed");
+    info!("Contact renamed");
+    state.activity.record(
+        ActivityKind::Updated,
+        Some(id),
+        format!("Renamed {old_name} to {}", contact.name),
+    );
+    let contact = contact.clone();
+    state.contacts_changed(&contacts, [id]);
+    drop(contacts);
+    render_html(
+        &state.app_state,
+        "contact.html",
+        &context! { contact => contact },
+        "name_cell",
+    )
+}
+
+// Marks the contact unverified and sends a verification link, when verification is on
+async fn require_verification(state: &ContactsAppState, contact: &mut Contact) {
+    if !state.app_state.config.email_verification {
+        return;
+    }
+    contact.verified = false;
+    let token = state.verification_tokens.issue(contact.id).await;
+    let link = format!(
+        "{}/contact/verify/{token}",
+        state.app_state.config.public_url
+    );
+    debug!("Verification link: {link}");
+    // Queued for a background worker so a slow or failing mail server never holds up the request
+    if let Some(mailer) = &state.app_state.mailer {
+        mailer.send_verification(contact, &link);
+    }
+}
+
+// A copy of the contact without the email, which has to stay unique, listed right after the
+// original in the default and the manual order. The row comes back with the email input open
+// for entering the copy's address.
+#[instrument(skip_all, fields(contact_id = id, copy_id, outcome))]
+async fn duplicate_contact_handler(
+    State(state): State<Arc<ContactsAppState>>,
+    Path(id): Path<usize>,
+) -> Result<Html<String>, AppError> {
+    let mut contacts = state.contacts.lock().await;
+    let Some(index) = contacts.iter().position(|contact| contact.id == id) else {
+        info!("Contact to duplicate does not exist");
+        return Err(AppError::NotFound("Contact does not exist".to_string()));
+    };
+    let source = &contacts[index];
+    let copy = Contact {
+        favorite: source.favorite,
+        archived: source.archived,
+        tags: source.tags.clone(),
+        phone: source.phone.clone(),
+        notes: source.notes.clone(),
+        order: source.order + 1,
+        ..Contact::new(&format!("{} (copy)", source.name), "")
+    };
+    state.activity.record(
+        ActivityKind::Created,
+        Some(copy.id),
+        format!("Duplicated {} as {}", source.name, copy.name),
+    );
+    // The manual order makes room behind the original. The default order is newest first,
+    // so there the copy goes in front of it.
+    let mut changed = vec![copy.id];
+    for contact in contacts
+        .iter_mut()
+        .filter(|contact| contact.order >= copy.order)
+    {
+        contact.order += 1;
+        changed.push(contact.id);
+    }
+    contacts.insert(index, copy.clone());
+    let favorites_count = count_favorites(&contacts);
+    state.contacts_changed(&contacts, changed);
+    drop(contacts);
+    Span::current()
+        .record("copy_id", copy.id)
+        .record("outcome", "duplicated");
+    info!("Contact duplicated");
+
+    let row = try_render_trimmed_block(
+        &state.app_state,
+        "contact.html",
+        &context! { contact => copy, edit_email => true, email => "" },
+        "contact",
+    )?;
+    let badge = try_render_trimmed_block(
+        &state.app_state,
+        "contacts.html",
+        &context! { favorites_count => favorites_count },
+        "oob_favorites_count",
+    )?;
+    Ok(Html(row + &badge))
+}
+
+#[instrument(skip_all, fields(contact_id = id, email_domain = redact_email(&form.email), outcome))]
+async fn update_email_handler(
+    State(state): State<Arc<ContactsAppState>>,
+    Path(id): Path<usize>,
+    Form(form): Form<EmailForm>,
+) -> Result<Html<String>, AppError> {
+    let mut contacts = state.contacts.lock().await;
+    let email = form.email.trim();
+    let input = ContactInput {
+        email: Some(email),
+        ..Default::default()
+    };
+    let invalid = validate_contact(&input, &state.app_state.config)
+        .errors
+        .remove("email");
+    let taken = contacts
+        .iter()
+        .any(|contact| contact.id != id && same_email(&contact.email, email));
+    let Some(contact) = contacts.iter_mut().find(|contact| contact.id == id) else {
+        info!("Contact to change the email of does not exist");
+        return Err(AppError::NotFound("Contact does not exist".to_string()));
+    };
+    let taken = invalid.is_none() && taken;
+    let error = invalid.or_else(|| taken.then(|| "Email already exists".to_string()));
+    if let Some(error) = error {
+        Span::current().record("outcome", if taken { "conflict" } else { "invalid" });
+        info!("Contact email rejected");
+        // The input keeps what was typed so it can be corrected
+        let input = try_render_block(
+            &state.app_state,
+            "contact.html",
+            &context! { contact => contact, edit_email => true, email => form.email, error => error },
+            "email_cell",
+        )?;
+        return Err(if taken {
+            AppError::Conflict(input)
+        } else {
+            AppError::Validation(input)
+        });
+    }
+    if contact.email != email {
+        let old_email = std::mem::replace(&mut contact.email, email.to_string());
+        field_history::record(
+            &mut contact.history,
+            state.app_state.config.field_history_size,
+            "email",
+            &old_email,
+            &contact.email,
+        );
+        contact.undeliverable = false;
+        require_verification(&state, contact).await;
+        state.activity.record(
+            ActivityKind::Updated,
+            Some(id),
+            format!("Changed the email of {}", contact.name),
+        );
+    }
+    Span::current().record("outcome", "updated");
+    info!("Contact email updated");
+    let contact = contact.clone();
+    state.contacts_changed(&contacts, [id]);
+    drop(contacts);
+    render_html(
+        &state.app_state,
+        "contact.html",
+        &context! { contact => contact },
+        "email_cell",
+    )
+}
+
+#[instrument(skip_all, fields(contact_id, outcome))]
+async fn verify_contact_handler(
+    State(state): State<Arc<ContactsAppState>>,
+    Path(token): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let (status, result, contact) = match state.verification_tokens.redeem(&token).await {
+        TokenCheck::Valid(id) => {
+            let mut contacts = state.contacts.lock().await;
+            match contacts.iter_mut().find(|contact| contact.id == id) {
+                Some(contact) => {
+                    contact.verified = true;
+                    Span::current()
+                        .record("contact_id", id)
+                        .record("outcome", "verified");
+                    info!("Contact verified");
+                    state.activity.record(
+                        ActivityKind::Updated,
+                        Some(id),
+                        format!("Verified the email of {}", contact.name),
+                    );
+                    let contact = contact.clone();
+                    state.contacts_changed(&contacts, [id]);
+                    (StatusCode::OK, "verified", Some(contact))
+                }
+                // The contact was deleted before it got verified
+                None => (StatusCode::NOT_FOUND, "invalid", None),
+            }
+        }
+        TokenCheck::Expired => {
+            Span::current().record("outcome", "expired");
+            (StatusCode::GONE, "expired", None)
+        }
+        TokenCheck::Unknown => (StatusCode::NOT_FOUND, "invalid", None),
+    };
+    let body = try_render_block(
+        &state.app_state,
+        "verify.html",
+        &context! { result => result, contact => contact },
+        "verify",
+    )?;
+    Ok(html_response(status, body))
+}
+
+#[instrument(skip_all, fields(contact_id = id, email_domain, outcome))]
+async fn delete_contact_handler(
+    State(state): State<Arc<ContactsAppState>>,
+    Path(id): Path<usize>,
+) -> Result<Html<String>, AppError> {
+    let mut contacts = state.contacts.lock().await;
+    let Some(index) = contacts.iter().position(|contact| contact.id == id) else {
+        Span::current().record("outcome", "not_found");
+        info!("Contact to delete does not exist");
+        return Err(AppError::NotFound("Contact does not exist".to_string()));
+    };
+    let removed = contacts.remove(index);
+    state.activity.record(
+        ActivityKind::Deleted,
+        Some(id),
+        format!("Deleted {}", removed.name),
+    );
+    Span::current()
+        .record("email_domain", redact_email(&removed.email))
+        .record("outcome", "deleted");
+    info!("Contact deleted");
+    state.contacts_changed(&contacts, [id]);
+    // htmx swaps the row's outerHTML with the empty body, removing it from the list
+    Ok(Html(String::new()))
+}
+
+// Imports the CSV or vCard file uploaded as the `file` field. Duplicates within the file are
+// merged first, then contacts whose email already exists are skipped. The rest are checked
+// like the add form's, invalid ones are listed by email in the summary.
+#[instrument(skip_all, fields(imported, merged, skipped, invalid, outcome))]
+async fn import_contacts_handler(
+    State(state): State<Arc<ContactsAppState>>,
+    mut multipart: Multipart,
+) -> Result<Html<String>, AppError> {
+    // Held from reading the file until its contacts are added. A burst of uploads is
+    // refused rather than queued, each waiting upload would hold its connection open.
+    let Ok(permit) = state.import_permits.try_acquire() else {
+        Span::current().record("outcome", "busy");
+        warn!("Import refused, too many running");
+        return Err(AppError::Busy(try_render_block(
+            &state.app_state,
+            "contacts.html",
+            &context! {},
+            "import_busy",
+        )?));
+    };
+    let mut data = None;
+    while let Some(field) = multipart.next_field().await.map_err(upload_error)? {
+        if field.name() == Some("file") {
+            data = Some(field.text().await.map_err(upload_error)?);
+            break;
+        }
+    }
+    let records = match data.as_deref().map(import::parse) {
+        Some(Ok(records)) => records,
+        Some(Err(err)) => {
+            Span::current().record("outcome", "invalid");
+            return Err(AppError::Validation(err));
+        }
+        None => {
+            Span::current().record("outcome", "invalid");
+            return Err(AppError::Validation("No file uploaded".to_string()));
+        }
+    };
+    let deduplicated = import::dedupe(records);
+    let config = &state.app_state.config;
+
+    let mut contacts = state.contacts.lock().await;
+    let existing: HashSet<String> = contacts
+        .iter()
+        .map(|contact| import::normalize_email(&contact.email))
+        .collect();
+    let (mut imported, mut skipped) = (0, 0);
+    let mut errors = Vec::new();
+    if deduplicated.invalid > 0 {
+        errors.push(format!("{} without an email", deduplicated.invalid));
+    }
+    let mut invalid = deduplicated.invalid;
+    for record in deduplicated.records {
+        if existing.contains(&record.email) {
+            skipped += 1;
+            continue;
+        }
+        let input = ContactInput {
+            name: Some(&record.name),
+            email: Some(&record.email),
+            phone: Some(&record.phone),
+            ..Default::default()
+        };
+        let mut rejection = validate_contact(&input, config);
+        let (phone, _) = check_phone(&record.phone, config, &mut rejection);
+        if let Some(err) = first_error(&mut rejection) {
+            errors.push(format!("{}: {err}", record.email));
+            invalid += 1;
+            continue;
+        }
+        let mut contact = Contact::new(&record.name, &record.email);
+        contact.phone = phone;
+        contacts.push(contact);
+        imported += 1;
+    }
+    drop(permit);
+    Span::current()
+        .record("imported", imported)
+        .record("merged", deduplicated.merged)
+        .record("skipped", skipped)
+        .record("invalid", invalid)
+        .record("outcome", "imported");
+    info!("Contacts imported");
+    if imported > 0 {
+        state.activity.record(
+            ActivityKind::Created,
+            None,
+            format!("Imported {imported} contacts from a file"),
+        );
+        // The imported contacts were added at the end
+        let added = contacts[contacts.len() - imported..].iter();
+        state.contacts_changed(&contacts, added.map(|contact| contact.id));
+    }
+
+    let summary = try_render_block(
+        &state.app_state,
+        "contacts.html",
+        &context! {
+            imported => imported,
+            merged => deduplicated.merged,
+            skipped => skipped,
+            invalid => invalid,
+            errors => errors,
+        },
+        "import_summary",
+    )?;
+    let query = ContactsQuery::default();
+    let page = ContactListPage::new(&contacts, &query, &state.app_state.config);
+    let list = try_render_block(
+        &state.app_state,
+        "contacts.html",
+        &context! { oob_list => true, ..page.context(&contacts) },
+        "contactlist",
+    )?;
+    Ok(Html(summary + &list))
+}
+
+// An upload that couldn't be read, 413 when it's over the body limit. A broken upload isn't
+// reported as a missing file, it may well have had one.
+fn upload_error(err: MultipartError) -> AppError {
+    Span::current().record("outcome", "invalid");
+    info!("Failed to read the upload: {err}");
+    if err.status() == StatusCode::PAYLOAD_TOO_LARGE {
+        AppError::TooLarge("The file is too large".to_string())
+    } else {
+        AppError::BadRequest(format!("The upload could not be read: {}", err.body_text()))
+    }
+}
+
+// Imports the JSON array or export object sent as the body. Ids are kept unless another
+// contact already has them. Invalid entries are reported by position, the rest still get
+// imported, and entries whose email already exists are skipped.
+#[instrument(skip_all, fields(imported, skipped, invalid, outcome))]
+async fn import_json_contacts_handler(
+    State(state): State<Arc<ContactsAppState>>,
+    headers: HeaderMap,
+    Json(body): Json<import::JsonImport>,
+) -> Result<Response, AppError> {
+    let config = &state.app_state.config;
+    let mut contacts = state.contacts.lock().await;
+    let mut emails: HashSet<String> = contacts
+        .iter()
+        .map(|contact| import::normalize_email(&contact.email))
+        .collect();
+    let mut ids: HashSet<usize> = contacts.iter().map(|contact| contact.id).collect();
+    let (mut imported, mut skipped) = (0, 0);
+    let mut errors = Vec::new();
+    for (index, entry) in body.into_contacts().into_iter().enumerate() {
+        let validated = entry.and_then(|mut contact| {
+            let input = ContactInput {
+                name: Some(&contact.name),
+                email: Some(&contact.email),
+                phone: Some(&contact.phone),
+                notes: Some(&contact.notes),
+                tags: Some(&contact.tags),
+            };
+            let mut rejection = validate_contact(&input, config);
+            let (phone, _) = check_phone(&contact.phone, config, &mut rejection);
+            if let Some(err) = first_error(&mut rejection) {
+                return Err(err);
+            }
+            // The next free id has to come after it
+            if contact.id.checked_add(1).is_none() {
+                return Err("id: Too large".to_string());
+            }
+            contact.phone = phone;
+            Ok(contact)
+        });
+        let mut contact = match validated {
+            Ok(contact) => contact,
+            Err(err) => {
+                errors.push(format!("Entry {index}: {err}"));
+                continue;
+            }
+        };
+        if !emails.insert(import::normalize_email(&contact.email)) {
+            skipped += 1;
+            continue;
+        }
+        if contact.id == 0 || ids.contains(&contact.id) {
+            contact.id = NEXT_ID.fetch_add(1, SeqCst);
+        }
+        // Later contacts must not be handed an id that was just imported
+        NEXT_ID.fetch_max(contact.id + 1, SeqCst);
+        ids.insert(contact.id);
+        contacts.push(contact);
+        imported += 1;
+    }
+    Span::current()
+        .record("imported", imported)
+        .record("skipped", skipped)
+        .record("invalid", errors.len())
+        .record("outcome", "imported");
+    info!("Contacts imported from JSON");
+    if imported > 0 {
+        state.activity.record(
+            ActivityKind::Created,
+            None,
+            format!("Imported {imported} contacts from JSON"),
+        );
+        // The imported contacts were added at the end
+        let added = contacts[contacts.len() - imported..].iter();
+        state.contacts_changed(&contacts, added.map(|contact| contact.id));
+    }
+
+    let wants_json = headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/json"));
+    if wants_json {
+        return Ok(Json(json!({
+            "imported": imported,
+            "skipped": skipped,
+            "errors": errors,
+        }))
+        .into_response());
+    }
+    let summary = try_render_block(
+        &state.app_state,
+        "contacts.html",
+        &context! {
+            imported => imported,
+            merged => 0,
+            skipped => skipped,
+            errors => errors,
+        },
+        "import_summary",
+    )?;
+    let query = ContactsQuery::default();
+    let page = ContactListPage::new(&contacts, &query, config);
+    let list = try_render_block(
+        &state.app_state,
+        "contacts.html",
+        &context! { oob_list => true, ..page.context(&contacts) },
+        "contactlist",
+    )?;
+    Ok(Html(summary + &list).into_response())
+}
+
+// Merges the contacts given as repeated `ids[]` into the `primary` one and deletes them.
+// The primary row is swapped out of band and the duplicate rows are removed from the page.
+#[instrument(skip_all, fields(contact_id, merged, outcome))]
+async fn merge_contacts_handler(
+    State(state): State<Arc<ContactsAppState>>,
+    FormFields(fields): FormFields,
+) -> Result<Html<String>, AppError> {
+    let reject = |message: &str| {
+        Span::current().record("outcome", "invalid");
+        info!("Rejected merge: {message}");
+        Err(AppError::Validation(message.to_string()))
+    };
+    let primary_id = form_field(&fields, "primary").and_then(|value| value.parse::<usize>().ok());
+    let duplicate_ids: Result<Vec<usize>, _> = fields
+        .iter()
+        .filter(|(key, _)| key == "ids[]" || key == "ids")
+        .map(|(_, value)| value.parse::<usize>())
+        .collect();
+    let (Some(primary_id), Ok(mut duplicate_ids)) = (primary_id, duplicate_ids) else {
+        return reject("Merging needs a primary id and numeric duplicate ids");
+    };
+    duplicate_ids.sort_unstable();
+    duplicate_ids.dedup();
+    duplicate_ids.retain(|id| *id != primary_id);
+    if duplicate_ids.is_empty() {
+        return reject("Select at least one duplicate besides the primary contact");
+    }
+    Span::current()
+        .record("contact_id", primary_id)
+        .record("merged", duplicate_ids.len());
+
+    // Everything is checked before the first change so a bad id leaves the contacts untouched
+    let mut contacts = state.contacts.lock().await;
+    let exists = |id: &usize| contacts.iter().any(|contact| contact.id == *id);
+    if let Some(missing) = std::iter::once(&primary_id)
+        .chain(&duplicate_ids)
+        .find(|id| !exists(id))
+    {
+        return reject(&format!("Contact {missing} does not exist"));
+    }
+    let (duplicates, rest): (Vec<_>, Vec<_>) = std::mem::take(&mut *contacts)
+        .into_iter()
+        .partition(|contact| duplicate_ids.contains(&contact.id));
+    *contacts = rest;
+    let primary = contacts
+        .iter_mut()
+        .find(|contact| contact.id == primary_id)
+        .expect("Checked above");
+    for duplicate in duplicates {
+        primary.merge(duplicate, state.app_state.config.max_tags);
+    }
+    let primary = primary.clone();
+    let changed = std::iter::once(primary_id).chain(duplicate_ids.iter().copied());
+    state.contacts_changed(&contacts, changed);
+    drop(contacts);
+    Span::current().record("outcome", "merged");
+    info!("Contacts merged");
+    state.activity.record(
+        ActivityKind::Updated,
+        Some(primary_id),
+        format!(
+            "Merged {} contacts into {}",
+            duplicate_ids.len(),
+            primary.name
+        ),
+    );
+
+    let mut body = try_render_trimmed_block(
+        &state.app_state,
+        "contact.html",
+        &context! { contact => primary, oob => true },
+        "contact",
+    )?;
+    for id in duplicate_ids {
+        body.push_str(&format!(
+            "<div id=\"contact-{id}\" hx-swap-oob=\"delete\"></div>"
+        ));
+    }
+    Ok(Html(body))
+}
+
+// Takes the ids in their new sequence as repeated `id[]` fields. The page already shows the
+// new order after the drag, so there's nothing to swap. A rejected reorder swaps the list
+// back in the order the server keeps, using the `list` field like the bulk actions do.
+#[instrument(skip_all, fields(contacts, outcome))]
+async fn reorder_contacts_handler(
+    State(state): State<Arc<ContactsAppState>>,
+    FormFields(fields): FormFields,
+) -> Result<StatusCode, AppError> {
+    let ids: Result<Vec<usize>, _> = fields
+        .iter()
+        .filter(|(key, _)| key == "id[]" || key == "id")
+        .map(|(_, value)| value.parse::<usize>())
+        .collect();
+    let Ok(ids) = ids else {
+        Span::current().record("outcome", "invalid");
+        return Err(AppError::Validation("Ids must be numbers".to_string()));
+    };
+    Span::current().record("contacts", ids.len());
+
+    // One lock for the whole reorder so concurrent reorders can't interleave
+    let mut contacts = state.contacts.lock().await;
+    let orders: Vec<i64> = contacts.iter().map(|contact| contact.order).collect();
+    if let Err(err) = sort::reorder(&mut contacts, &ids) {
+        Span::current().record("outcome", "invalid");
+        info!("Rejected reorder: {err}");
+        let query: ContactsQuery = form_field(&fields, "list")
+            .and_then(|list| serde_urlencoded::from_str(list).ok())
+            .unwrap_or_default();
+        let page = ContactListPage::new(&contacts, &query, &state.app_state.config);
+        let list = try_render_block(
+            &state.app_state,
+            "contacts.html",
+            &page.context(&contacts),
+            "contactlist",
+        )?;
+        return Err(AppError::Validation(err).restore("#contactlist-page", list));
+    }
+    // Renumbering can move contacts that weren't listed, the ones whose order differs changed
+    let moved = contacts
+        .iter()
+        .zip(orders)
+        .filter(|(contact, order)| contact.order != *order)
+        .map(|(contact, _)| contact.id);
+    state.contacts_changed(&contacts, moved.collect::<Vec<_>>());
+    drop(contacts);
+    Span::current().record("outcome", "reordered");
+    info!("Contacts reordered");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Swaps the row and updates the favorites badge out of band in the same response
+#[instrument(skip_all, fields(contact_id = id, favorite))]
+async fn favorite_contact_handler(
+    State(state): State<Arc<ContactsAppState>>,
+    Path(id): Path<usize>,
+) -> Result<Html<String>, AppError> {
+    let mut contacts = state.contacts.lock().await;
+    let Some(contact) = contacts.iter_mut().find(|contact| contact.id == id) else {
+        info!("Contact to favorite does not exist");
+        return Err(AppError::NotFound("Contact does not exist".to_string()));
+    };
+    contact.favorite = !contact.favorite;
+    Span::current().record("favorite", contact.favorite);
+    info!("Contact favorite toggled");
+    let description = if contact.favorite {
+        format!("Added {} to the favorites", contact.name)
+    } else {
+        format!("Removed {} from the favorites", contact.name)
+    };
+    state
+        .activity
+        .record(ActivityKind::Updated, Some(id), description);
+    let contact = contact.clone();
+    // Counted under the same lock so the badge matches the state right after the toggle
+    let favorites_count = count_favorites(&contacts);
+    state.contacts_changed(&contacts, [id]);
+    drop(contacts);
+
+    let row = try_render_trimmed_block(
+        &state.app_state,
+        "contact.html",
+        &context! { contact => contact },
+        "contact",
+    )?;
+    let badge = try_render_trimmed_block(
+        &state.app_state,
+        "contacts.html",
+        &context! { favorites_count => favorites_count },
+        "oob_favorites_count",
+    )?;
+    Ok(Html(row + &badge))
+}
+
+async fn admin_routes_handler(
+    State(state): State<Arc<AdminAppState>>,
+) -> Result<Html<String>, AppError> {
+    render_html(
+        &state.app_state,
+        "admin.html",
+        &context! { routes => state.routes },
+        "routes",
+    )
+}
+
+// Dumps the in-memory state as JSON, holding each lock only while copying it
+async fn admin_state_handler(State(state): State<Arc<AdminAppState>>) -> impl IntoResponse {
+    let counts = state.counter.counts.lock().await.clone();
+    let contacts = state.contacts.contacts.lock().await.clone();
+    Json(json!({ "counters": counts, "contacts": contacts }))
+}
+
+// Error responses by kind since startup
+async fn admin_errors_handler() -> impl IntoResponse {
+    Json(error::error_counts())
+}
+
+// Fragment cache hits and misses since startup
+async fn admin_cache_handler(State(state): State<Arc<AdminAppState>>) -> impl IntoResponse {
+    Json(state.app_state.fragment_cache.stats())
+}
+
+async fn export_snapshot_handler(State(state): State<Arc<AdminAppState>>) -> impl IntoResponse {
+    Json(Snapshot::capture(&state.counter, &state.contacts).await)
+}
+
+// Replaces all state with an earlier export, malformed JSON is rejected by the extractor
+async fn import_snapshot_handler(
+    State(state): State<Arc<AdminAppState>>,
+    Json(snapshot): Json<Snapshot>,
+) -> Result<String, AppError> {
+    if let Err(err) = snapshot.validate() {
+        return Err(AppError::Validation(err));
+    }
+    let imported = snapshot.contacts.len();
+    snapshot.restore(&state.counter, &state.contacts).await;
+    info!("Imported snapshot with {imported} contacts");
+    Ok(format!("Imported {imported} contacts"))
+}
+
+// Removes every contact, for resetting a demo. The `confirm` field has to repeat
+// CLEAR_CONFIRMATION so a stray request can't wipe the data. Returns the empty list.
+async fn clear_contacts_handler(
+    State(state): State<Arc<AdminAppState>>,
+    client_ip: ClientIp,
+    form: Result<Form<ClearForm>, FormRejection>,
+) -> Result<Html<String>, AppError> {
+    // A missing or malformed form counts as not confirmed
+    let confirmed = form.is_ok_and(|Form(form)| form.confirm.trim() == CLEAR_CONFIRMATION);
+    if !confirmed {
+        return Err(AppError::BadRequest(format!(
+            "Type \"{CLEAR_CONFIRMATION}\" to confirm"
+        )));
+    }
+    let mut contacts = state.contacts.contacts.lock().await;
+    let removed = contacts.len();
+    contacts.clear();
+    warn!(%client_ip, "Cleared all {removed} contacts");
+    state.contacts.activity.record(
+        ActivityKind::Deleted,
+        None,
+        format!("Cleared all {removed} contacts"),
+    );
+    // The next save writes the empty list, so the contacts don't come back on restart
+    state.contacts.contacts_replaced(&contacts);
+
+    let query = ContactsQuery::default();
+    let page = ContactListPage::new(&contacts, &query, &state.app_state.config);
+    render_html(
+        &state.app_state,
+        "contacts.html",
+        &page.context(&contacts),
+        "contactlist",
+    )
+}
+
+async fn login_page_handler(State(state): State<Arc<AppState>>) -> Result<Html<String>, AppError> {
+    render_html(&state, "login.html", &context! {}, "login")
+}
+
+// Starts a session for the right password and sends the browser to the start page
+async fn login_handler(
+    State(state): State<Arc<AppState>>,
+    client_ip: ClientIp,
+    headers: HeaderMap,
+    Form(form): Form<LoginForm>,
+) -> Result<Response, AppError> {
+    let (Some(login), Some(sessions)) = (&state.config.login, &state.sessions) else {
+        return Err(AppError::NotFound(
+            "This site does not exist :(".to_string(),
+        ));
+    };
+    let password_hash = login.password_hash.clone();
+    let matches = tokio::task::spawn_blocking(move || {
+        session::verify_password(&form.password, &password_hash)
+    })
+    .await
+    .unwrap_or(false);
+    if !matches {
+        warn!(%client_ip, "Login failed");
+        let form = try_render_block(
+            &state,
+            "login.html",
+            &context! { error => "Wrong password" },
+            "login_form",
+        )?;
+        return Err(AppError::Validation(form));
+    }
+    let token = sessions.start().await;
+    info!(%client_ip, "Logged in");
+    let cookie = [(header::SET_COOKIE, cookies::set(SESSION_COOKIE, &token))];
+    Ok((cookie, redirect(&headers, "/")).into_response())
+}
+
+async fn logout_handler(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    if let (Some(sessions), Some(token)) = (&state.sessions, cookies::get(&headers, SESSION_COOKIE))
+    {
+        sessions.end(&token).await;
+    }
+    let cookie = [(header::SET_COOKIE, cookies::clear(SESSION_COOKIE))];
+    (cookie, redirect(&headers, "/login")).into_response()
+}
+
+// Like `session::require_login`, htmx requests are sent on with HX-Redirect
+fn redirect(headers: &HeaderMap, to: &str) -> Response {
+    if headers.contains_key("hx-request") {
+        [("HX-Redirect", to.to_string())].into_response()
+    } else {
+        Redirect::to(to).into_response()
+    }
+}
+
+// The process is up and able to answer requests
+async fn livez_handler() -> impl IntoResponse {
+    (StatusCode::OK, "ok")
+}
+
+// Which build is running, from the crate version and what build.rs recorded
+async fn version_handler() -> impl IntoResponse {
+    let built_at = env!("BUILD_TIMESTAMP").parse().unwrap_or(0);
+    Json(json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "commit": env!("GIT_COMMIT_HASH"),
+        "built_at": activity::format_utc(built_at),
+    }))
+}
+
+// Each core template that doesn't load with the reason, e.g. a missing file or a syntax error
+fn unloadable_templates(tpl_env: &Environment) -> Vec<String> {
+    CORE_TEMPLATES
+        .iter()
+        .filter_map(|tpl_name| {
+            let err = tpl_env.get_template(tpl_name).err()?;
+            Some(format!("{tpl_name}: {err}"))
+        })
+        .collect()
+}
+
+// Ready once the core templates load and the data file's directory exists, if persistence is on
+async fn readyz_handler(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let missing_template = CORE_TEMPLATES
+        .iter()
+        .find(|tpl_name| state.tpl_env.get_template(tpl_name).is_err());
+    if let Some(tpl_name) = missing_template {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("template {tpl_name} is not loadable"),
+        );
+    }
+    if let Some(data_file) = &state.config.data_file {
+        let data_dir = data_file
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(std::path::Path::new("."));
+        if !data_dir.is_dir() {
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!("data directory {} does not exist", data_dir.display()),
+            );
+        }
+    }
+    (StatusCode::OK, "ready".to_string())
+}
+
+async fn not_found_handler() -> AppError {
+    AppError::NotFound("This site does not exist :(".to_string())
+}
+
+fn more_url(after: usize, limit: usize) -> String {
+    format!("/contacts/more?after={after}&limit={limit}")
+}
+
+// Section of the alphabetical view, names not starting with a letter go under "#"
+fn name_group(name: &str) -> String {
+    match name.trim_start().chars().next() {
+        Some(first) if first.is_alphabetic() => first.to_uppercase().collect(),
+        _ => "#".to_string(),
+    }
+}
+
+// Number of contacts each status filter shows
+fn status_counts(contacts: &[Contact]) -> Value {
+    let archived = contacts.iter().filter(|contact| contact.archived).count();
+    context! {
+        active => contacts.len() - archived,
+        archived => archived,
+        all => contacts.len(),
+    }
+}
+
+fn count_favorites(contacts: &[Contact]) -> usize {
+    contacts.iter().filter(|contact| contact.favorite).count()
+}
+
+// The id of the element htmx swaps the response into, from the HX-Target header. None for
+// requests not made by htmx and for targets without an id.
+fn hx_target(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get("hx-target")?
+        .to_str()
+        .ok()
+        .filter(|target| !target.is_empty())
+        .map(str::to_string)
+}
+
+// Checks whether the client's If-None-Match header contains the given ETag
+fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|candidate| candidate.trim().trim_start_matches("W/"))
+        .any(|candidate| candidate == "*" || candidate == etag)
+}
+
+// Only the domain of an email address ends up in the logs
+fn redact_email(email: &str) -> &str {
+    email_domain(email).unwrap_or("<none>")
+}
+
+// The address with its domain lowercased, None unless it looks like `local@domain`
+fn normalize_email(email: &str) -> Option<String> {
+    let (local, domain) = email.trim().rsplit_once('@')?;
+    let valid =
+        |part: &str| !part.is_empty() && !part.chars().any(|c| c.is_whitespace() || c.is_control());
+    (valid(local) && valid(domain) && !local.contains('@'))
+        .then(|| format!("{local}@{}", domain.to_lowercase()))
+}
+
+// All addresses go in Bcc so recipients don't see each other. Characters the URL could
+// misread, like `&` or `?`, are percent-encoded.
+fn mailto_bcc(addresses: &[String]) -> String {
+    let mut mailto = String::from("mailto:?bcc=");
+    for (i, address) in addresses.iter().enumerate() {
+        if i > 0 {
+            mailto.push(',');
+        }
+        for byte in address.bytes() {
+            if byte.is_ascii_alphanumeric() || b"@.-_+~".contains(&byte) {
+                mailto.push(byte as char);
+            } else {
+                let _ = write!(mailto, "%{byte:02X}");
+            }
+        }
+    }
+    mailto
+}
+
+// The part after the last `@`, as typed
+fn email_domain(email: &str) -> Option<&str> {
+    email.rsplit_once('@').map(|(_, domain)| domain)
+}
+
+// Names are only a soft duplicate check, so compare them loosely
+fn name_exists(name: &str, contacts: &[Contact]) -> bool {
+    let normalized = name.trim().to_lowercase();
+    contacts
+        .iter()
+        .any(|contact| contact.name.trim().to_lowercase() == normalized)
+}
+
+fn email_exists(email: &str, contacts: &[Contact]) -> bool {
+    for contact in contacts.iter() {
+        if same_email(&contact.email, email) {
+            return true;
+        }
+    }
+    false
+}
+
+// Compared like the imports do, ignoring case and surrounding whitespace. Contacts without an
+// email, like a fresh duplicate, don't clash with each other.
+fn same_email(a: &str, b: &str) -> bool {
+    let a = import::normalize_email(a);
+    !a.is_empty() && a == import::normalize_email(b)
+}
+
+// Roughly what browsers accept for an email input, plus a dot in the domain: a local part
+// and a domain of letters, digits and hyphens, without whitespace
+fn is_email_address(email: &str) -> bool {
+    let Some((local, domain)) = email.rsplit_once('@') else {
+        return false;
+    };
+    let valid_label = |label: &str| {
+        !label.is_empty()
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_alphanumeric() || c == '-')
+    };
+    !local.is_empty()
+        && !local.contains(|c: char| c.is_whitespace() || c == '@')
+        && domain.contains('.')
+        && domain.split('.').all(valid_label)
+}
+
+#[derive(Serialize)]
+struct FormRejectionData {
+    values: HashMap<String, String>,
+    errors: HashMap<String, String>,
+    // Non-blocking feedback, the submission still goes through
+    warnings: HashMap<String, String>,
+}
+
+impl FormRejectionData {
+    // Create a new form with empty values, errors and warnings
+    fn new() -> Self {
+        FormRejectionData {
+            values: HashMap::new(),
+            errors: HashMap::new(),
+            warnings: HashMap::new(),
+        }
+    }
+
+    // Keeps every submitted field as entered, so a rejected form comes back filled in
+    fn set_values(&mut self, form: &impl Serialize) {
+        if let Ok(serde_json::Value::Object(fields)) = serde_json::to_value(form) {
+            for (key, value) in fields {
+                if let serde_json::Value::String(value) = value {
+                    self.values.insert(key, value);
+                }
+            }
+        }
+    }
+
+    fn set_error(&mut self, key: &str, error: &str) {
+        self.errors.insert(key.into(), error.into());
+    }
+
+    fn set_warning(&mut self, key: &str, warning: &str) {
+        self.warnings.insert(key.into(), warning.into());
+    }
+}
+
+impl Default for FormRejectionData {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Default, Deserialize)]
+struct ContactsQuery {
+    page: Option<usize>,
+    per_page: Option<usize>,
+    sort: Option<String>,
+    q: Option<String>,
+    #[serde(default)]
+    status: StatusFilter,
+    // group=alpha shows the contacts in sections by the first letter of their name
+    group: Option<String>,
+    // scroll=infinite loads further contacts while scrolling and scroll=button with a
+    // "Load more" button, both instead of showing page links
+    scroll: Option<String>,
+    // domain=example.com only shows contacts with that email domain, ignoring case
+    domain: Option<String>,
+    // tag=work only shows contacts carrying that tag, ignoring case
+    tag: Option<String>,
+}
+
+// Which contacts the list shows, archived contacts are hidden by default
+#[derive(Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum StatusFilter {
+    #[default]
+    Active,
+    Archived,
+    All,
+}
+
+impl StatusFilter {
+    fn includes(self, contact: &Contact) -> bool {
+        match self {
+            StatusFilter::Active => !contact.archived,
+            StatusFilter::Archived => contact.archived,
+            StatusFilter::All => true,
+        }
+    }
+}
+
+impl ContactsQuery {
+    // Encodes the parameters that shape the list (everything but the page) for use in links
+    fn list_query(&self) -> String {
+        #[derive(Serialize)]
+        struct ListParams<'a> {
+            #[serde(skip_serializing_if = "Option::is_none")]
+            sort: Option<&'a str>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            q: Option<&'a str>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            status: Option<StatusFilter>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            group: Option<&'a str>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            scroll: Option<&'a str>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            domain: Option<&'a str>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            tag: Option<&'a str>,
+        }
+
+        serde_urlencoded::to_string(ListParams {
+            sort: self.sort.as_deref(),
+            q: self.q.as_deref(),
+            status: (self.status != StatusFilter::Active).then_some(self.status),
+            group: self.group.as_deref(),
+            scroll: self.scroll.as_deref(),
+            domain: self.domain.as_deref(),
+            tag: self.tag.as_deref(),
+        })
+        .unwrap_or_default()
+    }
+}
+
+// One page of the contact list, filtered, sorted and paginated the way the query asks for
+struct ContactListPage<'a> {
+    contacts: Vec<&'a Contact>,
+    pagination: Pagination,
+    list_query: String,
+    search_query: Option<&'a str>,
+    sort: Option<&'a str>,
+    // The domain filter, only set when some contact has that domain
+    domain: Option<&'a str>,
+    manual_order: bool,
+    status: StatusFilter,
+    grouped: bool,
+    // Where the last row loads the next batch from in infinite scroll mode
+    more_url: Option<String>,
+    load_more: bool,
+}
+
+impl<'a> ContactListPage<'a> {
+    fn new(contacts: &'a [Contact], query: &'a ContactsQuery, config: &Config) -> Self {
+        let search_query = query.q.as_deref().map(str::trim).filter(|q| !q.is_empty());
+        let has_domain = |contact: &Contact, domain: &str| {
+            email_domain(&contact.email).is_some_and(|d| d.eq_ignore_ascii_case(domain))
+        };
+        // An unknown domain shows the full list rather than an empty one
+        let domain = query
+            .domain
+            .as_deref()
+            .map(str::trim)
+            .filter(|domain| contacts.iter().any(|contact| has_domain(contact, domain)));
+        let tag = query
+            .tag
+            .as_deref()
+            .map(str::trim)
+            .filter(|tag| !tag.is_empty());
+
+        // Newest first unless a sort order is given, which keeps that order for ties
+        let mut listed_contacts: Vec<_> = contacts
+            .iter()
+            .rev()
+            .filter(|contact| query.status.includes(contact))
+            .filter(|contact| search_query.is_none_or(|q| search::matches(contact, q)))
+            .filter(|contact| domain.is_none_or(|domain| has_domain(contact, domain)))
+            .filter(|contact| {
+                tag.is_none_or(|tag| contact.tags.iter().any(|t| t.eq_ignore_ascii_case(tag)))
+            })
+            .collect();
+        let grouped = query.group.as_deref() == Some("alpha");
+        if grouped {
+            // Sections in alphabetical order with "#" last, names sorted within each
+            listed_contacts.sort_by_cached_key(|contact| {
+                let group = name_group(&contact.name);
+                (group == "#", group, contact.name.to_lowercase())
+            });
+        } else if let Some(sort) = &query.sort {
+            ContactSorter::parse(sort).sort(&mut listed_contacts);
+        }
+
+        let per_page = query
+            .per_page
+            .unwrap_or(config.contacts_per_page)
+            .min(MAX_PER_PAGE);
+        let pagination =
+            Pagination::paginate(&mut listed_contacts, query.page.unwrap_or(1), per_page);
+        // The cursor follows the default newest-first order, other views keep page links
+        let infinite = query.scroll.as_deref() == Some("infinite")
+            && query.status == StatusFilter::Active
+            && query.sort.is_none()
+            && !grouped
+            && search_query.is_none()
+            && domain.is_none()
+            && tag.is_none();
+        let more_url = listed_contacts
+            .last()
+            .filter(|_| infinite && pagination.next().is_some())
+            .map(|last| more_url(last.id, per_page));
+        Self {
+            contacts: listed_contacts,
+            pagination,
+            list_query: query.list_query(),
+            search_query,
+            sort: query.sort.as_deref(),
+            domain,
+            manual_order: query.sort.as_deref() == Some("manual"),
+            status: query.status,
+            grouped,
+            more_url,
+            load_more: query.scroll.as_deref() == Some("button"),
+        }
+    }
+
+    // Identifies the page within the fragment cache and the ETag
+    fn key(&self) -> String {
+        format!(
+            "{}:{}:{}",
+            self.pagination.page, self.pagination.per_page, self.list_query
+        )
+    }
+
+    fn context(&self, all_contacts: &[Contact]) -> Value {
+        let rows: Vec<_> = self
+            .contacts
+            .iter()
+            .map(|contact| match self.search_query {
+                Some(q) => Value::from_serialize(SearchHit::new(contact, q)),
+                None => Value::from_serialize(contact),
+            })
+            .collect();
+        // Consecutive rows of the same section, the page is already sorted by section
+        let groups = self.grouped.then(|| {
+            let mut groups: Vec<(String, Vec<Value>)> = Vec::new();
+            for (contact, row) in self.contacts.iter().zip(&rows) {
+                let group = name_group(&contact.name);
+                match groups.last_mut() {
+                    Some((letter, rows)) if *letter == group => rows.push(row.clone()),
+                    _ => groups.push((group, vec![row.clone()])),
+                }
+            }
+            groups
+                .into_iter()
+                .map(|(letter, contacts)| context! { letter, contacts })
+                .collect::<Vec<_>>()
+        });
+        context! {
+            groups => groups,
+            contacts => rows,
+            favorites_count => count_favorites(all_contacts),
+            pagination => self.pagination,
+            list_query => self.list_query,
+            manual_order => self.manual_order,
+            status => self.status,
+            status_counts => status_counts(all_contacts),
+            infinite => self.more_url.is_some(),
+            more_url => self.more_url,
+            load_more => self.load_more,
+            q => self.search_query,
+            sort => self.sort,
+            domain => self.domain,
+            formdata => FormRejectionData::new(),
+        }
+    }
+}
+
+enum BulkAction {
+    Delete,
+    Archive,
+    AddTag(String),
+    RemoveTag(String),
+    Favorite,
+}
+
+impl BulkAction {
+    // Returns how many of the selected contacts were changed
+    fn apply(&self, contacts: &mut Vec<Contact>, ids: &[usize], max_tags: usize) -> usize {
+        if let BulkAction::Delete = self {
+            let before = contacts.len();
+            contacts.retain(|contact| !ids.contains(&contact.id));
+            return before - contacts.len();
+        }
+        let mut affected = 0;
+        for contact in contacts
+            .iter_mut()
+            .filter(|contact| ids.contains(&contact.id))
+        {
+            let changed = match self {
+                BulkAction::Archive => !std::mem::replace(&mut contact.archived, true),
+                BulkAction::Favorite => !std::mem::replace(&mut contact.favorite, true),
+                BulkAction::AddTag(tag)
+                    if contact.tags.contains(tag) || contact.tags.len() >= max_tags =>
+                {
+                    false
+                }
+                BulkAction::AddTag(tag) => {
+                    contact.tags.push(tag.clone());
+                    true
+                }
+                BulkAction::RemoveTag(tag) => {
+                    let before = contact.tags.len();
+                    contact.tags.retain(|t| t != tag);
+                    contact.tags.len() != before
+                }
+                BulkAction::Delete => false,
+            };
+            affected += usize::from(changed);
+        }
+        affected
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            BulkAction::Delete => "deleted",
+            BulkAction::Archive => "archived",
+            BulkAction::AddTag(_) => "tagged",
+            BulkAction::RemoveTag(_) => "untagged",
+            BulkAction::Favorite => "favorited",
+        }
+    }
+}
+
+// Form body as a list of pairs, for forms that repeat a field like `ids[]`. Capped at
+// max_form_fields so a single request can't hold the contacts lock for long.
+struct FormFields(Vec<(String, String)>);
+
+impl FromRequest<Arc<ContactsAppState>> for FormFields {
+    type Rejection = Response;
+
+    async fn from_request(
+        request: Request<Body>,
+        state: &Arc<ContactsAppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let Form(fields) = Form::<Vec<(String, String)>>::from_request(request, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        let max_fields = state.app_state.config.max_form_fields;
+        if fields.len() > max_fields {
+            let message = format!("At most {max_fields} form fields are accepted");
+            return Err(AppError::Validation(message).into_response());
+        }
+        Ok(Self(fields))
+    }
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    #[serde(default)]
+    format: HistoryFormat,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum HistoryFormat {
+    #[default]
+    Json,
+    Svg,
+}
+
+#[derive(Deserialize)]
+struct ActivityQuery {
+    page: Option<usize>,
+    per_page: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct DeltaQuery {
+    since_version: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct PollQuery {
+    #[serde(default)]
+    since: u64,
+    // The list's own query, e.g. page=2&sort=name
+    #[serde(default)]
+    list: String,
+}
+
+#[derive(Deserialize)]
+struct MoreQuery {
+    after: Option<usize>,
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    #[serde(default)]
+    q: String,
+    #[serde(default)]
+    fuzzy: bool,
+}
+
+#[derive(Deserialize, Serialize)]
+struct FormData {
+    // Missing ones are reported like empty ones, see check_new_contact
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    email: String,
+    #[serde(default)]
+    phone: String,
+    #[serde(default)]
+    notes: String,
+    #[serde(default)]
+    birthday: String,
+}
+
+#[derive(Deserialize)]
+struct LoginForm {
+    #[serde(default)]
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct ClearForm {
+    #[serde(default)]
+    confirm: String,
+}
+
+#[derive(Deserialize)]
+struct NameForm {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct EmailForm {
+    email: String,
+}
+
+struct ContactsAppState {
+    app_state: Arc<AppState>,
+    contacts: Mutex<Vec<Contact>>,
+    changes: ContactChanges,
+    verification_tokens: VerificationTokens,
+    activity: ActivityLog,
+    // Only set with MX_CHECK=1
+    mx_checker: Option<MxChecker>,
+    // Bounds the uploaded files held in memory at once, see `import_contacts_handler`
+    import_permits: Semaphore,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Contact {
+    name: String,
+    email: String,
+    id: usize,
+    // Contacts saved before verification existed count as verified
+    #[serde(default = "default_verified")]
+    verified: bool,
+    #[serde(default)]
+    favorite: bool,
+    // Position in the manual order (sort=manual), new contacts go last
+    #[serde(default)]
+    order: i64,
+    // Archived contacts are hidden from the list
+    #[serde(default)]
+    archived: bool,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    phone: String,
+    #[serde(default)]
+    notes: String,
+    // The email domain had no MX record at the last check, see verify_emails_handler
+    #[serde(default)]
+    undeliverable: bool,
+    // YYYY-MM-DD, or --MM-DD without the year, empty when not known
+    #[serde(default)]
+    birthday: String,
+    // Edits of single fields, oldest first and capped at FIELD_HISTORY_SIZE
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    history: Vec<FieldChange>,
+}
+
+struct AdminAppState {
+    app_state: Arc<AppState>,
+    counter: Arc<CounterAppState>,
+    contacts: Arc<ContactsAppState>,
+    routes: Vec<RouteInfo>,
+}
+
+struct CounterAppState {
+    app_state: Arc<AppState>,
+    counts: Mutex<HashMap<String, usize>>,
+    counter_file: Option<CounterFile>,
+    // Recent values for the activity sparkline, not persisted
+    history: Mutex<CounterHistory>,
+}
+
+struct AppState {
+    tpl_env: Environment<'static>,
+    fragment_cache: FragmentCache,
+    persistence: Option<Persistence>,
+    mailer: Option<Mailer>,
+    // Only set when logging in is required
+    sessions: Option<Sessions>,
+    config: Config,
+}
+
+// Relative to the working directory
+const TEMPLATES_DIR: &str = "templates/";
+
+// Templates every page depends on
+const CORE_TEMPLATES: [&str; 3] = ["base.html", "counter.html", "contacts.html"];
+
+// Counter behind the plain /counter routes
+const DEFAULT_COUNTER: &str = "default";
+
+const MAX_COUNTER_NAME_LENGTH: usize = 64;
+
+// Remembers the last contact search, see remember_search
+const SEARCH_COOKIE: &str = "contacts_q";
+// Remembers the chosen sort order, see remember_sort
+const SORT_COOKIE: &str = "contacts_sort";
+
+// Recently viewed contacts, see contact_handler
+const RECENT_COOKIE: &str = "recent_contacts";
+const MAX_RECENT_CONTACTS: usize = 5;
+
+// Upper bound for the per_page query parameter
+const MAX_PER_PAGE: usize = 100;
+
+// Has to be typed to clear all contacts, see clear_contacts_handler
+const CLEAR_CONFIRMATION: &str = "delete all contacts";
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+impl Contact {
+    fn new(name: &str, email: &str) -> Self {
+        let id = NEXT_ID.fetch_add(1, SeqCst);
+        Self {
+            name: name.to_string(),
+            email: email.to_string(),
+            id,
+            verified: true,
+            favorite: false,
+            order: id as i64,
+            archived: false,
+            tags: Vec::new(),
+            phone: String::new(),
+            notes: String::new(),
+            undeliverable: false,
+            birthday: String::new(),
+            history: Vec::new(),
+        }
+    }
+}
+
+impl Contact {
+    // Takes over the other contact's fields where this one has none, notes are kept from
+    // both and tags are combined
+    fn merge(&mut self, other: Contact, max_tags: usize) {
+        if self.phone.is_empty() {
+            self.phone = other.phone;
+        }
+        if self.birthday.is_empty() {
+            self.birthday = other.birthday;
+        }
+        if !other.notes.is_empty() && other.notes != self.notes {
+            if self.notes.is_empty() {
+                self.notes = other.notes;
+            } else {
+                self.notes = format!("{}\n{}", self.notes, other.notes);
+            }
+        }
+        for tag in other.tags {
+            if !self.tags.contains(&tag) && self.tags.len() < max_tags {
+                self.tags.push(tag);
+            }
+        }
+        self.favorite |= other.favorite;
+    }
+}
+
+fn default_verified() -> bool {
+    true
+}
+
+impl ContactsAppState {
+    fn new(
+        app_state: Arc<AppState>,
+        contacts: Vec<Contact>,
+        mx_checker: Option<MxChecker>,
+    ) -> Self {
+        Self {
+            verification_tokens: VerificationTokens::new(app_state.config.verification_ttl),
+            activity: ActivityLog::new(app_state.config.activity_log_size),
+            import_permits: Semaphore::new(app_state.config.max_concurrent_imports.max(1)),
+            app_state,
+            changes: ContactChanges::new(&contacts),
+            contacts: Mutex::new(contacts),
+            mx_checker,
+        }
+    }
+
+    // Call after every change to the contacts with them still locked and the ids of the
+    // ones created, changed or removed. Drops the fragments rendered from them, schedules a
+    // save and wakes the long polls.
+    fn contacts_changed(&self, contacts: &[Contact], changed: impl IntoIterator<Item = usize>) {
+        self.changes.bump(contacts, changed);
+        self.app_state.fragment_cache.invalidate("contacts");
+        self.app_state.mark_dirty();
+    }
+
+    // Like contacts_changed, for when the whole list was replaced
+    fn contacts_replaced(&self, contacts: &[Contact]) {
+        self.changes.bump_all(contacts);
+        self.app_state.fragment_cache.invalidate("contacts");
+        self.app_state.mark_dirty();
+    }
+}
+
+impl AdminAppState {
+    fn new(
+        app_state: Arc<AppState>,
+        counter: Arc<CounterAppState>,
+        contacts: Arc<ContactsAppState>,
+        routes: Vec<RouteInfo>,
+    ) -> Self {
+        Self {
+            app_state,
+            counter,
+            contacts,
+            routes,
+        }
+    }
+}
+
+impl CounterAppState {
+    fn new(
+        app_state: Arc<AppState>,
+        counts: HashMap<String, usize>,
+        counter_file: Option<CounterFile>,
+    ) -> Self {
+        let history = CounterHistory::new(app_state.config.counter_history_size);
+        Self {
+            app_state,
+            counts: Mutex::new(counts),
+            counter_file,
+            history: Mutex::new(history),
         }
     }
 }
 
 impl AppState {
-    fn new(tpl_env: Environment<'static>) -> Self {
-        Self { tpl_env }
-    }
-}
-
-fn render_block(state: &AppState, tpl_name: &str, tpl_ctx: &Value, tpl_blk: &str) -> String {
-    let tpl = state
-        .tpl_env
-        .get_template(tpl_name)
-        .expect("Failed to get template");
-    let mut tpl_state = tpl
-        .eval_to_state(tpl_ctx)
-        .expect("Failed to evaluate template");
-    tpl_state
-        .render_block(tpl_blk)
-        .expect("Failed  to render block")
+    fn new(tpl_env: Environment<'static>, config: Config, mailer: Option<Mailer>) -> Self {
+        Self {
+            tpl_env,
+            fragment_cache: FragmentCache::new(config.fragment_cache_size),
+            persistence: config.data_file.clone().map(Persistence::new),
+            mailer,
+            sessions: config.login.as_ref().map(Sessions::new),
+            config,
+        }
+    }
+
+    // Schedules the state to be written to the data file, if persistence is enabled
+    fn mark_dirty(&self) {
+        if let Some(persistence) = &self.persistence {
+            persistence.mark_dirty();
+        }
+    }
+}
+
+// Renders the add-contact form on its own. htmx disables the submit button while the
+// request is in flight, and since the response replaces the whole form the button
+// always comes back enabled, after a rejection just like after a successful add.
+fn render_form(state: &AppState, formdata: &FormRejectionData) -> Result<String, minijinja::Error> {
+    try_render_block(
+        state,
+        "contacts.html",
+        &context! { formdata => formdata },
+        "form",
+    )
+}
+
+// The usual handler response, a rendered fragment with the given status
+fn html_response(status: StatusCode, body: String) -> impl IntoResponse {
+    (status, Html(body))
+}
+
+// Renders a single block as the whole response body, failing with the render error
+fn render_html(
+    state: &AppState,
+    tpl_name: &str,
+    tpl_ctx: &Value,
+    tpl_blk: &str,
+) -> Result<Html<String>, AppError> {
+    Ok(Html(try_render_block(state, tpl_name, tpl_ctx, tpl_blk)?))
+}
+
+// For fragments swapped in by position, like rows appended to the list, or out of band.
+// Whitespace around them would end up in the page as text of its own.
+fn try_render_trimmed_block(
+    state: &AppState,
+    tpl_name: &str,
+    tpl_ctx: &Value,
+    tpl_blk: &str,
+) -> Result<String, minijinja::Error> {
+    try_render_block(state, tpl_name, tpl_ctx, tpl_blk).map(|html| html.trim().to_string())
+}
+
+// Like try_render_block, but reuses an earlier render of the same block from the fragment
+// cache. Only use this for blocks whose scope gets invalidated when their inputs change.
+fn render_cached_block(
+    state: &AppState,
+    scope: &str,
+    tpl_name: &str,
+    tpl_ctx: &Value,
+    tpl_blk: &str,
+    ctx_key: &str,
+) -> Result<String, minijinja::Error> {
+    state
+        .fragment_cache
+        .get_or_render(scope, tpl_name, tpl_blk, ctx_key, || {
+            try_render_block(state, tpl_name, tpl_ctx, tpl_blk)
+        })
+}
+
+fn try_render_block(
+    state: &AppState,
+    tpl_name: &str,
+    tpl_ctx: &Value,
+    tpl_blk: &str,
+) -> Result<String, minijinja::Error> {
+    let start = Instant::now();
+    let tpl = state.tpl_env.get_template(tpl_name)?;
+    // render_captured would render every block of the template, including ones
+    // whose context is missing here, eval_to_state only sets up the state
+    #[allow(deprecated)]
+    let mut tpl_state = tpl.eval_to_state(tpl_ctx)?;
+    let rendered = tpl_state.render_block(tpl_blk)?;
+
+    let elapsed = start.elapsed();
+    let render_ms = elapsed.as_secs_f64() * 1000.0;
+    if elapsed >= state.config.slow_render_threshold {
+        warn!(
+            template = tpl_name,
+            block = tpl_blk,
+            render_ms,
+            "Slow block render"
+        );
+    } else {
+        debug!(
+            template = tpl_name,
+            block = tpl_blk,
+            render_ms,
+            "Rendered block"
+        );
+    }
+    Ok(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Templates from TEMPLATES_DIR with the defaults, no persistence, mail or login
+    fn test_app_state() -> Arc<AppState> {
+        let config = Config::from_env();
+        let tpl_env = template_env(config.features, assets::STYLESHEET_URL, false);
+        Arc::new(AppState::new(tpl_env, config, None))
+    }
+
+    fn test_contacts_state(contacts: Vec<Contact>) -> Arc<ContactsAppState> {
+        Arc::new(ContactsAppState::new(test_app_state(), contacts, None))
+    }
+
+    fn contact_form(name: &str, email: &str) -> FormData {
+        FormData {
+            name: name.to_string(),
+            email: email.to_string(),
+            phone: "555 1234".to_string(),
+            notes: "Met at the \"conference\"".to_string(),
+            birthday: "--04-01".to_string(),
+        }
+    }
+
+    async fn body_text(response: Response) -> String {
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn only_html_templates_are_escaped() {
+        let mut tpl_env = template_env(Config::from_env().features, assets::STYLESHEET_URL, false);
+        for name in ["export.csv", "avatar.svg", "page.html", "page.htm"] {
+            tpl_env.add_template_owned(name, "{{ value }}").unwrap();
+        }
+        let render = |name: &str| {
+            let tpl = tpl_env.get_template(name).unwrap();
+            tpl.render(context! { value => "Tom & \"Jerry\" <tj@example.com>" })
+                .unwrap()
+        };
+        assert_eq!(render("export.csv"), "Tom & \"Jerry\" <tj@example.com>");
+        assert_eq!(render("avatar.svg"), "Tom & \"Jerry\" <tj@example.com>");
+        assert_eq!(
+            render("page.html"),
+            "Tom &amp; &quot;Jerry&quot; &lt;tj@example.com&gt;"
+        );
+        assert_eq!(render("page.htm"), render("page.html"));
+    }
+
+    #[test]
+    fn flag_tests_follow_the_contact_fields() {
+        let tpl_env = template_env(Config::from_env().features, assets::STYLESHEET_URL, false);
+        let mut contact = Contact::new("Ann", "ann@example.com");
+        contact.favorite = true;
+        contact.verified = false;
+        let flags = tpl_env
+            .render_str(
+                "{% if contact is favorite %}favorite{% endif %}\
+                 {% if contact is archived %} archived{% endif %}\
+                 {% if contact is verified %} verified{% endif %}\
+                 {% if contact is undeliverable %} undeliverable{% endif %}",
+                context! { contact => contact },
+            )
+            .unwrap();
+        assert_eq!(flags, "favorite");
+        let missing = tpl_env
+            .render_str(
+                "{% if value is favorite %}favorite{% endif %}",
+                context! { value => "text" },
+            )
+            .unwrap();
+        // Values without the field don't pass, rather than failing the render
+        assert_eq!(missing, "");
+    }
+
+    #[test]
+    fn trimmed_block_has_no_surrounding_whitespace() {
+        let state = test_app_state();
+        let tpl_ctx = context! { contact => Contact::new("Ann", "ann@example.com") };
+        let untrimmed = try_render_block(&state, "contacts.html", &tpl_ctx, "oob_contact").unwrap();
+        assert_ne!(untrimmed.trim(), untrimmed);
+        let trimmed =
+            try_render_trimmed_block(&state, "contacts.html", &tpl_ctx, "oob_contact").unwrap();
+        assert_eq!(trimmed, untrimmed.trim());
+        assert!(
+            trimmed.starts_with('<') && trimmed.ends_with('>'),
+            "{trimmed:?}"
+        );
+    }
+
+    #[test]
+    fn block_tags_leave_no_blank_lines() {
+        let tpl_env = template_env(Config::from_env().features, assets::STYLESHEET_URL, false);
+        let rendered = tpl_env
+            .render_str(
+                "<ul>\n    {% for item in items %}\n    <li>{{ item }}</li>\n    {% endfor %}\n</ul>",
+                context! { items => ["a", "b"] },
+            )
+            .unwrap();
+        assert_eq!(rendered, "<ul>\n    <li>a</li>\n    <li>b</li>\n</ul>");
+    }
+
+    #[tokio::test]
+    async fn trailing_slash_is_served_like_the_route() {
+        let counter_state = Arc::new(CounterAppState::new(test_app_state(), HashMap::new(), None));
+        let router = Router::new()
+            .route("/counter", get(counter_handler))
+            .with_state(counter_state);
+        let app = Router::new().fallback_service(router.map_request(trim_trailing_slash));
+
+        let request = Request::get("/counter/").body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(body_text(response)
+            .await
+            .contains(&format!("id=\"increment-{DEFAULT_COUNTER}\"")));
+    }
+
+    #[test]
+    fn trailing_slash_is_trimmed_outside_static() {
+        let path = |uri: &str| {
+            let request = Request::get(uri).body(Body::empty()).unwrap();
+            trim_trailing_slash(request).uri().to_string()
+        };
+        assert_eq!(path("/contacts/?page=2"), "/contacts?page=2");
+        assert_eq!(path("/"), "/");
+        assert_eq!(path("/static/images/"), "/static/images/");
+    }
+
+    // Contact 0, 1, ... in the order they were added, the even ones tagged work
+    fn tagged_contacts(count: usize) -> Vec<Contact> {
+        (0..count)
+            .map(|i| {
+                let mut contact =
+                    Contact::new(&format!("Contact {i}"), &format!("c{i}@example.com"));
+                if i % 2 == 0 {
+                    contact.tags.push("work".to_string());
+                }
+                contact
+            })
+            .collect()
+    }
+
+    #[test]
+    fn filtered_pages_count_only_matching_contacts() {
+        let contacts = tagged_contacts(10);
+        let config = Config::from_env();
+        let query: ContactsQuery =
+            serde_urlencoded::from_str("tag=work&page=2&per_page=2").unwrap();
+        let page = ContactListPage::new(&contacts, &query, &config);
+
+        assert_eq!(page.pagination.total, 5);
+        assert_eq!(page.pagination.total_pages, 3);
+        assert_eq!(page.pagination.page, 2);
+        let names: Vec<_> = page.contacts.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, ["Contact 4", "Contact 2"]);
+
+        let state = test_app_state();
+        let html = try_render_block(
+            &state,
+            "contacts.html",
+            &page.context(&contacts),
+            "pagination",
+        )
+        .unwrap();
+        assert!(html.contains("5 contacts, page 2 of 3"), "{html}");
+    }
+
+    #[tokio::test]
+    async fn rejected_form_keeps_every_submitted_value() {
+        let state = test_contacts_state(Vec::new());
+        let form = FormData {
+            birthday: "someday".to_string(),
+            ..contact_form("Ann", "ann@example.com")
+        };
+        let response = add_contact_handler(State(state.clone()), HeaderMap::new(), Form(form))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let html = body_text(response).await;
+        for value in [
+            "value=\"Ann\"",
+            "value=\"ann@example.com\"",
+            "value=\"555 1234\"",
+            "value=\"Met at the &quot;conference&quot;\"",
+            "value=\"someday\"",
+        ] {
+            assert!(html.contains(value), "{value} missing in {html}");
+        }
+        assert!(html.contains("Not a date, use YYYY-MM-DD or --MM-DD"));
+        assert!(state.contacts.lock().await.is_empty());
+    }
+
+    async fn post_json(
+        state: &Arc<ContactsAppState>,
+        body: &str,
+    ) -> (StatusCode, serde_json::Value) {
+        let app = Router::new()
+            .route("/contact", post(add_contact_json_handler))
+            .with_state(state.clone());
+        let request = Request::post("/contact")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let status = response.status();
+        (
+            status,
+            serde_json::from_str(&body_text(response).await).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn invalid_json_contact_gets_errors_by_field() {
+        let state = test_contacts_state(Vec::new());
+        let long_phone = "5".repeat(state.app_state.config.max_phone_length + 1);
+        let body = json!({ "name": " ", "email": "ann@example.com", "phone": long_phone });
+        let (status, errors) = post_json(&state, &body.to_string()).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            errors,
+            json!({ "errors": {
+                "name": "Name is required",
+                "phone": format!("At most {} characters", state.app_state.config.max_phone_length),
+            } })
+        );
+        assert!(state.contacts.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn json_contact_with_a_taken_email_conflicts() {
+        let state = test_contacts_state(vec![Contact::new("Ann", "ann@example.com")]);
+        let (status, errors) =
+            post_json(&state, r#"{"name": "Ann", "email": "ann@example.com"}"#).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(
+            errors,
+            json!({ "errors": { "email": "Email already exists" } })
+        );
+
+        let (status, errors) = post_json(&state, r#"{"name": 5}"#).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(errors["errors"]["body"].is_string());
+    }
+
+    #[tokio::test]
+    async fn new_contacts_are_stored_trimmed() {
+        let state = test_contacts_state(Vec::new());
+        let body = json!({ "name": " Ann ", "email": " Ann@example.com ", "notes": " hi " });
+        let (status, contact) = post_json(&state, &body.to_string()).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(contact["email"], "Ann@example.com");
+        let contacts = state.contacts.lock().await;
+        assert_eq!(
+            (contacts[0].name.as_str(), contacts[0].notes.as_str()),
+            ("Ann", "hi")
+        );
+        assert!(email_exists("ann@example.com", &contacts));
+    }
+
+    #[tokio::test]
+    async fn expired_session_redirects_htmx_to_login() {
+        let mut config = Config::from_env();
+        config.login = Some(session::LoginConfig {
+            password_hash: String::new(),
+            timeout: Duration::ZERO,
+            sliding: true,
+        });
+        let tpl_env = template_env(config.features, assets::STYLESHEET_URL, false);
+        let app_state = Arc::new(AppState::new(tpl_env, config, None));
+        let token = app_state.sessions.as_ref().unwrap().start().await;
+        let app = Router::new()
+            .route("/", get(|| async { "logged in" }))
+            .layer(middleware::from_fn_with_state(
+                app_state,
+                session::require_login,
+            ));
+
+        let request = Request::get("/")
+            .header("hx-request", "true")
+            .header(header::COOKIE, format!("{SESSION_COOKIE}={token}"))
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()["hx-redirect"], "/login");
+        assert!(response.headers()[header::SET_COOKIE]
+            .to_str()
+            .unwrap()
+            .starts_with(&format!("{SESSION_COOKIE}=;")));
+    }
+
+    #[tokio::test]
+    async fn adding_and_deleting_bump_the_version() {
+        let state = test_contacts_state(Vec::new());
+        let start = state.changes.version();
+
+        let form = contact_form("Ann", "ann@example.com");
+        let response = add_contact_handler(State(state.clone()), HeaderMap::new(), Form(form))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let added = state.changes.version();
+        assert!(added > start);
+
+        let id = state.contacts.lock().await[0].id;
+        let deleted = delete_contact_handler(State(state.clone()), Path(id)).await;
+        assert!(deleted.is_ok());
+        assert!(state.changes.version() > added);
+
+        // A rejected request leaves the version alone
+        let form = contact_form("", "");
+        let version = state.changes.version();
+        let rejected = add_contact_handler(State(state.clone()), HeaderMap::new(), Form(form))
+            .await
+            .into_response();
+        assert_eq!(rejected.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(state.changes.version(), version);
+    }
+
+    #[tokio::test]
+    async fn changes_announce_the_new_version() {
+        let state = test_contacts_state(vec![Contact::new("Ann", "ann@example.com")]);
+        let id = state.contacts.lock().await[0].id;
+        let app = Router::new()
+            .route(
+                "/contact/{id}",
+                axum::routing::delete(delete_contact_handler),
+            )
+            .layer(middleware::from_fn_with_state(
+                state.clone(),
+                changes::announce_version,
+            ))
+            .with_state(state.clone());
+
+        let request = Request::delete(format!("/contact/{id}"))
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let trigger: serde_json::Value =
+            serde_json::from_str(response.headers()["hx-trigger"].to_str().unwrap()).unwrap();
+        assert_eq!(
+            trigger["contacts-changed"]["version"],
+            state.changes.version()
+        );
+    }
+
+    #[tokio::test]
+    async fn imported_records_are_validated_like_the_form() {
+        let mut config = Config::from_env();
+        config.phone_default_country = Some(phonenumber::country::Id::US);
+        let tpl_env = template_env(config.features, assets::STYLESHEET_URL, false);
+        let app_state = Arc::new(AppState::new(tpl_env, config, None));
+        let long_name = "a".repeat(app_state.config.max_name_length + 1);
+        let state = Arc::new(ContactsAppState::new(
+            app_state,
+            vec![Contact::new("Ann", "ann@example.com")],
+            None,
+        ));
+        let app = Router::new()
+            .route("/contacts/import", post(import_contacts_handler))
+            .with_state(state.clone());
+
+        let csv = format!(
+            "name,email,phone\n\
+             Bob,bob@example.com,(201) 555-0123\n\
+             Ann again,ANN@example.com,\n\
+             {long_name},long@example.com,\n\
+             Carol,carol@example.com,555\n\
+             Dave,,\n"
+        );
+        let body = format!(
+            "--boundary\r\nContent-Disposition: form-data; name=\"file\"; \
+             filename=\"contacts.csv\"\r\nContent-Type: text/csv\r\n\r\n{csv}\r\n--boundary--\r\n"
+        );
+        let request = Request::post("/contacts/import")
+            .header(
+                header::CONTENT_TYPE,
+                "multipart/form-data; boundary=boundary",
+            )
+            .body(Body::from(body))
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let html = body_text(response).await;
+        assert!(
+            html.contains(
+                "Imported 1, merged within file 0, skipped (already existed) 1, invalid 3"
+            ),
+            "{html}"
+        );
+        assert!(html.contains("<li>1 without an email</li>"));
+        assert!(html.contains(&format!(
+            "<li>long@example.com: name: At most {} characters</li>",
+            state.app_state.config.max_name_length
+        )));
+        assert!(html.contains("<li>carol@example.com: phone: Not a valid phone number</li>"));
+
+        let contacts = state.contacts.lock().await;
+        let bob = contacts
+            .iter()
+            .find(|c| c.email == "bob@example.com")
+            .unwrap();
+        assert_eq!(bob.phone, "+12015550123");
+        assert_eq!(contacts.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn json_imports_check_phones_and_ids() {
+        let mut config = Config::from_env();
+        config.phone_default_country = Some(phonenumber::country::Id::US);
+        let tpl_env = template_env(config.features, assets::STYLESHEET_URL, false);
+        let app_state = Arc::new(AppState::new(tpl_env, config, None));
+        let state = Arc::new(ContactsAppState::new(app_state, Vec::new(), None));
+        let body = json!([
+            { "name": "Bob", "email": "bob@example.com", "phone": "(201) 555-0123" },
+            { "name": "Carol", "email": "carol@example.com", "phone": "555" },
+            { "id": usize::MAX, "name": "Dave", "email": "dave@example.com" },
+        ]);
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, HeaderValue::from_static("application/json"));
+
+        let response = import_json_contacts_handler(
+            State(state.clone()),
+            headers,
+            Json(serde_json::from_value(body).unwrap()),
+        )
+        .await
+        .into_response();
+        let summary: serde_json::Value = serde_json::from_str(&body_text(response).await).unwrap();
+        assert_eq!(
+            summary,
+            json!({
+                "imported": 1,
+                "skipped": 0,
+                "errors": [
+                    "Entry 1: phone: Not a valid phone number",
+                    "Entry 2: id: Too large",
+                ],
+            })
+        );
+        let contacts = state.contacts.lock().await;
+        assert_eq!(contacts[0].phone, "+12015550123");
+    }
+
+    #[tokio::test]
+    async fn unreadable_uploads_are_not_missing_files() {
+        let state = test_contacts_state(Vec::new());
+        let app = Router::new()
+            .route("/contacts/import", post(import_contacts_handler))
+            .layer(axum::extract::DefaultBodyLimit::max(256))
+            .with_state(state);
+        let upload = |csv: &str, end: &str| {
+            let body = format!(
+                "--boundary\r\nContent-Disposition: form-data; name=\"file\"; \
+                 filename=\"contacts.csv\"\r\n\r\n{csv}{end}"
+            );
+            Request::post("/contacts/import")
+                .header(
+                    header::CONTENT_TYPE,
+                    "multipart/form-data; boundary=boundary",
+                )
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        let large = "Ann,ann@example.com\n".repeat(20);
+        let response = app
+            .clone()
+            .oneshot(upload(&large, "\r\n--boundary--\r\n"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let response = app.oneshot(upload("Ann", "")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let text = body_text(response).await;
+        assert!(text.contains("could not be read"), "{text}");
+    }
+
+    #[tokio::test]
+    async fn form_and_json_reject_the_same_input_alike() {
+        let state = test_contacts_state(Vec::new());
+        let long_notes = "n".repeat(state.app_state.config.max_notes_length + 1);
+        let form = FormData {
+            notes: long_notes.clone(),
+            ..contact_form("", "ann at example.com")
+        };
+        let body = json!({ "name": "", "email": "ann at example.com", "notes": long_notes });
+
+        let (status, json_errors) = post_json(&state, &body.to_string()).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        let json_errors = json_errors["errors"].as_object().unwrap().clone();
+        let mut fields: Vec<_> = json_errors.keys().map(String::as_str).collect();
+        fields.sort_unstable();
+        assert_eq!(fields, ["email", "name", "notes"]);
+
+        let response = add_contact_handler(State(state.clone()), HeaderMap::new(), Form(form))
+            .await
+            .into_response();
+        assert_eq!(response.status(), status);
+        let html = body_text(response).await;
+        for error in json_errors.values() {
+            assert!(
+                html.contains(error.as_str().unwrap()),
+                "{error} missing in {html}"
+            );
+        }
+        assert!(state.contacts.lock().await.is_empty());
+    }
+
+    #[test]
+    fn email_format_is_checked() {
+        let config = Config::from_env();
+        let error = |email: &str| {
+            let input = ContactInput {
+                email: Some(email),
+                ..Default::default()
+            };
+            validate_contact(&input, &config).errors.remove("email")
+        };
+        for valid in [
+            "ann@example.com",
+            " Ann.Lee+work@mail.example.co.uk ",
+            "jörg@bücher.de",
+        ] {
+            assert_eq!(error(valid), None, "{valid}");
+        }
+        for invalid in [
+            "ann",
+            "ann@",
+            "@example.com",
+            "ann@example",
+            "ann lee@example.com",
+            "ann@exa mple.com",
+            "ann@example..com",
+            "ann@-example.com",
+        ] {
+            assert_eq!(
+                error(invalid).as_deref(),
+                Some("Not a valid email address"),
+                "{invalid}"
+            );
+        }
+        assert_eq!(error(" ").as_deref(), Some("Email is required"));
+    }
+
+    #[test]
+    fn emails_are_unique_ignoring_case() {
+        let contacts = [
+            Contact::new("Ann", "Ann@Example.com"),
+            Contact::new("Copy", ""),
+        ];
+        assert!(email_exists("ann@example.com", &contacts));
+        assert!(email_exists(" ANN@EXAMPLE.COM ", &contacts));
+        assert!(!email_exists("bob@example.com", &contacts));
+        assert!(!email_exists("", &contacts));
+    }
+
+    #[tokio::test]
+    async fn changed_email_may_not_differ_only_in_case() {
+        let state = test_contacts_state(vec![
+            Contact::new("Ann", "ann@example.com"),
+            Contact::new("Bob", "bob@example.com"),
+        ]);
+        let bob = state.contacts.lock().await[1].id;
+        let form = EmailForm {
+            email: " ANN@example.com".to_string(),
+        };
+        let response = update_email_handler(State(state.clone()), Path(bob), Form(form))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert!(body_text(response).await.contains("Email already exists"));
+
+        // Changing the case of a contact's own email is fine
+        let form = EmailForm {
+            email: "Bob@Example.com".to_string(),
+        };
+        let response = update_email_handler(State(state.clone()), Path(bob), Form(form))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(state.contacts.lock().await[1].email, "Bob@Example.com");
+    }
+
+    #[test]
+    fn over_length_name_is_rejected() {
+        let config = Config::from_env();
+        let name = "a".repeat(config.max_name_length + 1);
+        let input = ContactInput {
+            name: Some(&name),
+            email: Some("ann@example.com"),
+            ..Default::default()
+        };
+        let rejection = validate_contact(&input, &config);
+        assert_eq!(
+            rejection.errors["name"],
+            format!("At most {} characters", config.max_name_length)
+        );
+        assert!(!rejection.errors.contains_key("email"));
+
+        // Characters, not bytes, count towards the limit
+        let name = "ä".repeat(config.max_name_length);
+        let input = ContactInput {
+            name: Some(&name),
+            ..Default::default()
+        };
+        assert!(validate_contact(&input, &config).errors.is_empty());
+    }
+
+    #[test]
+    fn over_length_email_is_rejected() {
+        let config = Config::from_env();
+        let email = format!("{}@example.com", "a".repeat(config.max_email_length));
+        let input = ContactInput {
+            name: Some("Ann"),
+            email: Some(&email),
+            ..Default::default()
+        };
+        let rejection = validate_contact(&input, &config);
+        assert_eq!(
+            rejection.errors["email"],
+            format!("At most {} characters", config.max_email_length)
+        );
+        assert!(!rejection.errors.contains_key("name"));
+    }
+
+    #[test]
+    fn too_many_tags_are_rejected() {
+        let config = Config::from_env();
+        let tags: Vec<String> = (0..=config.max_tags).map(|i| format!("tag{i}")).collect();
+        let input = ContactInput {
+            tags: Some(&tags),
+            ..Default::default()
+        };
+        assert_eq!(
+            validate_contact(&input, &config).errors["tags"],
+            format!("At most {} tags", config.max_tags)
+        );
+    }
 }
@@ -0,0 +1,121 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use futures_util::{stream, StreamExt};
+use hickory_resolver::{
+    net::{DnsError, NetError, NoRecords},
+    proto::op::ResponseCode,
+    TokioResolver,
+};
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+
+// A domain's mail setup rarely changes, so answers are reused for an hour
+const CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+
+pub struct MxCheckConfig {
+    // Most lookups in flight at once (MX_CHECK_CONCURRENCY)
+    pub concurrency: usize,
+    // A new run is refused until this long after the last one started (MX_CHECK_INTERVAL_SECS)
+    pub min_interval: Duration,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum MxStatus {
+    Found,
+    // The domain doesn't exist or has no MX record, mail to it can't be delivered
+    Missing,
+    // No answer, e.g. a timeout, nothing is known about the domain
+    Failed,
+}
+
+// Looks up the MX records of email domains with the system's DNS configuration
+pub struct MxChecker {
+    resolver: TokioResolver,
+    concurrency: usize,
+    min_interval: Duration,
+    // Failed lookups aren't cached so the next run tries them again
+    cache: Mutex<HashMap<String, (MxStatus, Instant)>>,
+    last_run: Mutex<Option<Instant>>,
+}
+
+impl MxChecker {
+    // None when the system's DNS configuration can't be read
+    pub fn new(config: MxCheckConfig) -> Option<Self> {
+        let resolver = TokioResolver::builder_tokio()
+            .and_then(|builder| builder.build())
+            .inspect_err(|err| warn!("MX checks disabled, failed to set up DNS: {err}"))
+            .ok()?;
+        Some(Self {
+            resolver,
+            concurrency: config.concurrency.max(1),
+            min_interval: config.min_interval,
+            cache: Mutex::new(HashMap::new()),
+            last_run: Mutex::new(None),
+        })
+    }
+
+    // Starts a run, or returns how long to wait when the last one started too recently
+    pub async fn start_run(&self) -> Result<(), Duration> {
+        let mut last_run = self.last_run.lock().await;
+        if let Some(wait) = last_run
+            .map(|started| self.min_interval.saturating_sub(started.elapsed()))
+            .filter(|wait| !wait.is_zero())
+        {
+            return Err(wait);
+        }
+        *last_run = Some(Instant::now());
+        Ok(())
+    }
+
+    // Looks up every domain once, answering from the cache where possible
+    pub async fn check(&self, domains: HashSet<String>) -> HashMap<String, MxStatus> {
+        let mut results = HashMap::new();
+        let mut pending = Vec::new();
+        {
+            let cache = self.cache.lock().await;
+            for domain in domains {
+                match cache.get(&domain) {
+                    Some((status, checked_at)) if checked_at.elapsed() < CACHE_TTL => {
+                        results.insert(domain, *status);
+                    }
+                    _ => pending.push(domain),
+                }
+            }
+        }
+        let looked_up: Vec<(String, MxStatus)> = stream::iter(pending)
+            .map(|domain| async move {
+                let status = self.lookup(&domain).await;
+                (domain, status)
+            })
+            .buffer_unordered(self.concurrency)
+            .collect()
+            .await;
+
+        let mut cache = self.cache.lock().await;
+        for (domain, status) in looked_up {
+            if status != MxStatus::Failed {
+                cache.insert(domain.clone(), (status, Instant::now()));
+            }
+            results.insert(domain, status);
+        }
+        results
+    }
+
+    async fn lookup(&self, domain: &str) -> MxStatus {
+        // The trailing dot keeps the resolver from trying its search domains first
+        match self.resolver.mx_lookup(format!("{domain}.")).await {
+            Ok(_) => MxStatus::Found,
+            Err(NetError::Dns(DnsError::NoRecordsFound(NoRecords {
+                response_code: ResponseCode::NXDomain | ResponseCode::NoError,
+                ..
+            }))) => MxStatus::Missing,
+            Err(err) => {
+                debug!(domain, "MX lookup failed: {err}");
+                MxStatus::Failed
+            }
+        }
+    }
+}
@@ -0,0 +1,23 @@
+use std::sync::Arc;
+
+use axum::{
+    http::HeaderValue,
+    response::{IntoResponse, Response},
+};
+use minijinja::context;
+
+use crate::{error::AppError, try_render_block, AppState};
+
+// Answers requests past MAX_IN_FLIGHT with 503, nothing was done. Like in read-only mode the
+// notice is swapped in out of band and the request's target stays as it is.
+pub async fn overloaded(state: Arc<AppState>) -> Response {
+    let notice = match try_render_block(&state, "notice.html", &context! {}, "overloaded") {
+        Ok(notice) => notice,
+        Err(err) => return AppError::from(err).into_response(),
+    };
+    let mut response = AppError::Busy(notice).into_response();
+    response
+        .headers_mut()
+        .insert("HX-Reswap", HeaderValue::from_static("none"));
+    response
+}
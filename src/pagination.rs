@@ -0,0 +1,106 @@
+use serde::Serialize;
+
+// Position of the current page within a paginated list
+#[derive(Clone, Copy, Serialize)]
+pub struct Pagination {
+    pub page: usize,
+    pub per_page: usize,
+    pub total: usize,
+    pub total_pages: usize,
+}
+
+impl Pagination {
+    // Clamps the requested page into the valid range, pages are 1-based
+    pub fn new(page: usize, per_page: usize, total: usize) -> Self {
+        let per_page = per_page.max(1);
+        let total_pages = total.div_ceil(per_page).max(1);
+        Self {
+            page: page.clamp(1, total_pages),
+            per_page,
+            total,
+            total_pages,
+        }
+    }
+
+    // Counts the already filtered and sorted items, then keeps only the current page of them
+    pub fn paginate<T>(items: &mut Vec<T>, page: usize, per_page: usize) -> Self {
+        let pagination = Self::new(page, per_page, items.len());
+        items.truncate(pagination.range().end);
+        items.drain(..pagination.range().start);
+        pagination
+    }
+
+    // Index range of the current page's items
+    pub fn range(&self) -> std::ops::Range<usize> {
+        let start = ((self.page - 1) * self.per_page).min(self.total);
+        let end = (start + self.per_page).min(self.total);
+        start..end
+    }
+
+    pub fn prev(&self) -> Option<usize> {
+        (self.page > 1).then(|| self.page - 1)
+    }
+
+    pub fn next(&self) -> Option<usize> {
+        (self.page < self.total_pages).then(|| self.page + 1)
+    }
+
+    // Builds an RFC 8288 Link header with only the relations that exist for the current page.
+    // The path may already carry a query string, e.g. the active sort order.
+    pub fn link_header(&self, path: &str) -> String {
+        let separator = if path.contains('?') { '&' } else { '?' };
+        let url =
+            |page: usize| format!("<{path}{separator}page={page}&per_page={}>", self.per_page);
+        let mut links = Vec::new();
+        if let Some(prev) = self.prev() {
+            links.push(format!("{}; rel=\"first\"", url(1)));
+            links.push(format!("{}; rel=\"prev\"", url(prev)));
+        }
+        if let Some(next) = self.next() {
+            links.push(format!("{}; rel=\"next\"", url(next)));
+            links.push(format!("{}; rel=\"last\"", url(self.total_pages)));
+        }
+        links.join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clamps_the_page_into_range() {
+        assert_eq!(Pagination::new(0, 10, 25).page, 1);
+        assert_eq!(Pagination::new(9, 10, 25).page, 3);
+        assert_eq!(Pagination::new(1, 10, 0).total_pages, 1);
+    }
+
+    #[test]
+    fn paginate_keeps_only_the_current_page() {
+        let mut items: Vec<usize> = (1..=25).collect();
+        let pagination = Pagination::paginate(&mut items, 3, 10);
+        assert_eq!(items, [21, 22, 23, 24, 25]);
+        assert_eq!(pagination.total, 25);
+    }
+
+    #[test]
+    fn link_header_on_a_middle_page() {
+        assert_eq!(
+            Pagination::new(2, 10, 25).link_header("/contacts"),
+            "</contacts?page=1&per_page=10>; rel=\"first\", \
+             </contacts?page=1&per_page=10>; rel=\"prev\", \
+             </contacts?page=3&per_page=10>; rel=\"next\", \
+             </contacts?page=3&per_page=10>; rel=\"last\""
+        );
+    }
+
+    #[test]
+    fn link_header_leaves_out_missing_relations() {
+        assert_eq!(
+            Pagination::new(1, 10, 25).link_header("/contacts?sort=name"),
+            "</contacts?sort=name&page=2&per_page=10>; rel=\"next\", \
+             </contacts?sort=name&page=3&per_page=10>; rel=\"last\""
+        );
+        assert_eq!(Pagination::new(1, 10, 5).link_header("/contacts"), "");
+    }
+}
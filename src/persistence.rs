@@ -0,0 +1,154 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering::SeqCst},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::{fs, task::JoinHandle, time};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+use crate::{snapshot::Snapshot, ContactsAppState, CounterAppState};
+
+// Write-behind JSON persistence: handlers only mark the state dirty,
+// a background task writes it out periodically and once more on shutdown
+pub struct Persistence {
+    path: PathBuf,
+    dirty: AtomicBool,
+}
+
+impl Persistence {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    pub fn mark_dirty(&self) {
+        self.dirty.store(true, SeqCst);
+    }
+
+    // Reads the state saved by a previous run, a missing file means a fresh start
+    pub async fn load_from_disk(&self) -> Option<Snapshot> {
+        let json = match fs::read(&self.path).await {
+            Ok(json) => json,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return None,
+            Err(err) => panic!("Failed to read {}: {err}", self.path.display()),
+        };
+        let snapshot: Snapshot = serde_json::from_slice(&json)
+            .unwrap_or_else(|err| panic!("Invalid data in {}: {err}", self.path.display()));
+        if let Err(err) = snapshot.validate() {
+            panic!("Invalid data in {}: {err}", self.path.display());
+        }
+        info!("Loaded state from {}", self.path.display());
+        Some(snapshot)
+    }
+
+    // Writes the state if anything changed since the last save
+    pub async fn save_to_disk(
+        &self,
+        counter: &CounterAppState,
+        contacts: &ContactsAppState,
+    ) -> std::io::Result<()> {
+        if !self.dirty.swap(false, SeqCst) {
+            return Ok(());
+        }
+        let snapshot = Snapshot::capture(counter, contacts).await;
+        let result = write_atomically(&self.path, &serde_json::to_vec_pretty(&snapshot)?).await;
+        if result.is_err() {
+            // Try again on the next flush
+            self.mark_dirty();
+        }
+        result
+    }
+}
+
+// Writes to a temporary file first so a crash mid-write can't corrupt the saved state
+pub async fn write_atomically(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, contents).await?;
+    fs::rename(&tmp_path, path).await
+}
+
+// The counters on their own, written right after every change (COUNTER_FILE)
+pub struct CounterFile {
+    path: PathBuf,
+}
+
+impl CounterFile {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    // A missing or unreadable file means starting over, counters aren't worth failing for
+    pub async fn load(&self) -> Option<HashMap<String, usize>> {
+        let json = match fs::read(&self.path).await {
+            Ok(json) => json,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return None,
+            Err(err) => {
+                warn!("Failed to read {}: {err}", self.path.display());
+                return None;
+            }
+        };
+        match serde_json::from_slice(&json) {
+            Ok(counts) => {
+                info!("Loaded counters from {}", self.path.display());
+                Some(counts)
+            }
+            Err(err) => {
+                warn!(
+                    "Ignoring invalid counters in {}: {err}",
+                    self.path.display()
+                );
+                None
+            }
+        }
+    }
+
+    // Best effort, a failed write is logged and the next change tries again
+    pub async fn save(&self, counts: &HashMap<String, usize>) {
+        let result = match serde_json::to_vec_pretty(counts) {
+            Ok(json) => write_atomically(&self.path, &json).await,
+            Err(err) => Err(err.into()),
+        };
+        if let Err(err) = result {
+            warn!("Failed to save counters to {}: {err}", self.path.display());
+        }
+    }
+}
+
+// Flushes every interval and a final time after the server stopped accepting connections
+pub fn spawn_flush_task(
+    interval: Duration,
+    counter: Arc<CounterAppState>,
+    contacts: Arc<ContactsAppState>,
+    shutdown: CancellationToken,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let persistence = contacts
+            .app_state
+            .persistence
+            .as_ref()
+            .expect("Flush task needs persistence");
+        let mut interval = time::interval(interval);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {},
+                _ = shutdown.cancelled() => break,
+            }
+            match persistence.save_to_disk(&counter, &contacts).await {
+                Ok(()) => debug!("Flushed state"),
+                Err(err) => warn!("Failed to save state: {err}"),
+            }
+        }
+        match persistence.save_to_disk(&counter, &contacts).await {
+            Ok(()) => info!("Saved state to {}", persistence.path.display()),
+            Err(err) => warn!("Failed to save state on shutdown: {err}"),
+        }
+    })
+}
@@ -0,0 +1,21 @@
+use phonenumber::{country, Mode};
+
+pub enum PhoneCheck {
+    // In E.164 form, e.g. +4930123456
+    Normalized(String),
+    // Not recognizable as a phone number at all, kept as entered
+    Unparsed,
+    // Recognizable, but not a number that can exist in its country
+    Invalid,
+}
+
+// Numbers without a leading + are read as national numbers of the default country
+pub fn normalize(phone: &str, default_country: country::Id) -> PhoneCheck {
+    match phonenumber::parse(Some(default_country), phone) {
+        Ok(number) if phonenumber::is_valid(&number) => {
+            PhoneCheck::Normalized(number.format().mode(Mode::E164).to_string())
+        }
+        Ok(_) => PhoneCheck::Invalid,
+        Err(_) => PhoneCheck::Unparsed,
+    }
+}
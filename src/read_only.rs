@@ -0,0 +1,39 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::HeaderValue,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use minijinja::context;
+
+use crate::{error::AppError, try_render_block, AppState};
+
+// POST routes that only read, they keep working in read-only mode
+const READ_ONLY_POSTS: [&str; 2] = ["/contact/{id}/copy-email", "/contacts/compose"];
+
+// Refuses every request that could change data with 403 (READ_ONLY=1), for public demos.
+// The notice is swapped in out of band, whatever element the request targeted stays as it is.
+pub async fn reject_changes(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let exempt = request
+        .extensions()
+        .get::<MatchedPath>()
+        .is_some_and(|path| READ_ONLY_POSTS.contains(&path.as_str()));
+    if request.method().is_safe() || exempt {
+        return next.run(request).await;
+    }
+    let notice = match try_render_block(&state, "notice.html", &context! {}, "read_only") {
+        Ok(notice) => notice,
+        Err(err) => return AppError::from(err).into_response(),
+    };
+    let mut response = AppError::Forbidden(notice).into_response();
+    response
+        .headers_mut()
+        .insert("HX-Reswap", HeaderValue::from_static("none"));
+    response
+}
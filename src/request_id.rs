@@ -0,0 +1,58 @@
+use std::fmt::Write;
+
+use axum::{
+    extract::{Request, State},
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+
+pub const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+// Longest id taken over from a proxy
+const MAX_LENGTH: usize = 64;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+// Gives every request an id, in the request span as `request_id`, in the X-Request-Id
+// response header and in the events sent with the HX-Trigger headers, so a report from the
+// browser can be matched to the logs. With `trust_proxy` set an id from the proxy in front
+// is kept, a client could send anything.
+pub async fn assign(State(trust_proxy): State<bool>, mut request: Request, next: Next) -> Response {
+    let forwarded = trust_proxy
+        .then(|| request.headers().get(X_REQUEST_ID))
+        .flatten()
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| is_valid(id))
+        .map(str::to_string);
+    let id = forwarded.unwrap_or_else(generate);
+    let value = HeaderValue::from_str(&id).expect("Request ids are valid header values");
+    request.headers_mut().insert(X_REQUEST_ID, value.clone());
+    let mut response = REQUEST_ID.scope(id, next.run(request)).await;
+    response.headers_mut().insert(X_REQUEST_ID, value);
+    response
+}
+
+// The id of the request being handled, None outside of one, e.g. in a background task
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+fn is_valid(id: &str) -> bool {
+    (1..=MAX_LENGTH).contains(&id.len())
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+// 128 random bits as hex
+fn generate() -> String {
+    rand::random::<[u8; 16]>()
+        .iter()
+        .fold(String::with_capacity(32), |mut id, byte| {
+            let _ = write!(id, "{byte:02x}");
+            id
+        })
+}
@@ -0,0 +1,27 @@
+use serde::Serialize;
+
+// Every path and method registered on the router, axum's Router can't list them itself
+#[derive(Default)]
+pub struct RouteRegistry {
+    routes: Vec<RouteInfo>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct RouteInfo {
+    pub method: &'static str,
+    pub path: &'static str,
+}
+
+impl RouteRegistry {
+    // Records the route and hands back the path, so registering reads
+    // `.route(routes.add("GET", "/path"), get(handler))`
+    pub fn add(&mut self, method: &'static str, path: &'static str) -> &'static str {
+        self.routes.push(RouteInfo { method, path });
+        path
+    }
+
+    pub fn into_routes(mut self) -> Vec<RouteInfo> {
+        self.routes.sort_by_key(|route| (route.path, route.method));
+        self.routes
+    }
+}
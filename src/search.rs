@@ -0,0 +1,179 @@
+use std::fmt::Write;
+
+use fuzzy_matcher::{skim::SkimMatcherV2, FuzzyMatcher};
+use minijinja::HtmlEscape;
+use serde::Serialize;
+
+use crate::Contact;
+
+// A contact together with its fields rendered with the search matches highlighted
+#[derive(Serialize)]
+pub struct SearchHit<'a> {
+    #[serde(flatten)]
+    pub contact: &'a Contact,
+    pub name_html: String,
+    pub email_html: String,
+    // How well a fuzzy search matched, higher is better
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score: Option<i64>,
+}
+
+impl<'a> SearchHit<'a> {
+    pub fn new(contact: &'a Contact, query: &str) -> Self {
+        Self {
+            contact,
+            name_html: highlight(&contact.name, query),
+            email_html: highlight(&contact.email, query),
+            score: None,
+        }
+    }
+}
+
+// Ranks the contacts by their best fuzzy match over name and email, keeping at most `limit`
+// that score at least `threshold`. Equal scores keep the order the contacts came in.
+pub fn fuzzy_search<'a>(
+    contacts: impl IntoIterator<Item = &'a Contact>,
+    query: &str,
+    threshold: i64,
+    limit: usize,
+) -> Vec<SearchHit<'a>> {
+    let matcher = SkimMatcherV2::default().ignore_case();
+    let mut hits: Vec<_> = contacts
+        .into_iter()
+        .filter_map(|contact| {
+            let score = [&contact.name, &contact.email]
+                .into_iter()
+                .filter_map(|field| matcher.fuzzy_match(field, query))
+                .max()?;
+            (score >= threshold).then(|| SearchHit {
+                score: Some(score),
+                ..SearchHit::new(contact, query)
+            })
+        })
+        .collect();
+    hits.sort_by_key(|hit| std::cmp::Reverse(hit.score));
+    hits.truncate(limit);
+    hits
+}
+
+// Phone-style queries like `555-12` match the phone digits, everything else is a
+// case-insensitive substring match against the name or email
+pub fn matches(contact: &Contact, query: &str) -> bool {
+    if let Some(digits) = phone_digits(query) {
+        return phone_digits(&contact.phone).is_some_and(|phone| phone.contains(&digits));
+    }
+    let query = query.to_lowercase();
+    contact.name.to_lowercase().contains(&query) || contact.email.to_lowercase().contains(&query)
+}
+
+// The digits of a text made only of digits and the usual phone separators, None otherwise
+fn phone_digits(text: &str) -> Option<String> {
+    let is_phone_char = |c: char| c.is_ascii_digit() || " -+()./".contains(c);
+    if !text.chars().all(is_phone_char) {
+        return None;
+    }
+    let digits: String = text.chars().filter(char::is_ascii_digit).collect();
+    (!digits.is_empty()).then_some(digits)
+}
+
+// Escapes the text for HTML and wraps every case-insensitive occurrence of the query in <mark>
+pub fn highlight(text: &str, query: &str) -> String {
+    let needle: Vec<char> = query.chars().flat_map(char::to_lowercase).collect();
+    if needle.is_empty() {
+        return HtmlEscape(text).to_string();
+    }
+
+    // Lowercased characters, each tagged with the byte offset of the original character.
+    // Lowercasing can expand one character into several, so offsets may repeat.
+    let haystack: Vec<(usize, char)> = text
+        .char_indices()
+        .flat_map(|(offset, c)| c.to_lowercase().map(move |lower| (offset, lower)))
+        .collect();
+    let offset_at = |i: usize| haystack.get(i).map_or(text.len(), |(offset, _)| *offset);
+    // A match may only start and end on the boundary of an original character
+    let is_boundary = |i: usize| i == 0 || i == haystack.len() || offset_at(i - 1) != offset_at(i);
+
+    let mut out = String::with_capacity(text.len());
+    let mut copied_until = 0;
+    let mut i = 0;
+    while i + needle.len() <= haystack.len() {
+        let end = i + needle.len();
+        let is_match = haystack[i..end]
+            .iter()
+            .map(|(_, c)| *c)
+            .eq(needle.iter().copied());
+        if is_match && is_boundary(i) && is_boundary(end) {
+            let (start_offset, end_offset) = (offset_at(i), offset_at(end));
+            let before = &text[copied_until..start_offset];
+            let matched = &text[start_offset..end_offset];
+            let _ = write!(
+                out,
+                "{}<mark>{}</mark>",
+                HtmlEscape(before),
+                HtmlEscape(matched)
+            );
+            copied_until = end_offset;
+            i = end;
+        } else {
+            i += 1;
+        }
+    }
+    let _ = write!(out, "{}", HtmlEscape(&text[copied_until..]));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn contact(name: &str, email: &str, phone: &str) -> Contact {
+        serde_json::from_value(json!({ "id": 1, "name": name, "email": email, "phone": phone }))
+            .unwrap()
+    }
+
+    #[test]
+    fn phone_style_queries_match_the_phone_digits() {
+        let contact = contact("Ann 555", "ann@example.com", "+1 (555) 123-4567");
+        assert!(matches(&contact, "555-12"));
+        assert!(matches(&contact, "1234567"));
+        assert!(matches(&contact, "(555) 123"));
+        assert!(!matches(&contact, "555-99"));
+    }
+
+    #[test]
+    fn text_queries_match_name_or_email_ignoring_case() {
+        let contact = contact("Ann Lee", "ann@Example.com", "555 1234");
+        assert!(matches(&contact, "LEE"));
+        assert!(matches(&contact, "example.COM"));
+        assert!(!matches(&contact, "bob"));
+        // Digits mixed with letters are a text query, not a phone number
+        assert!(!matches(&contact, "555 a"));
+    }
+
+    #[test]
+    fn highlights_every_match_ignoring_case() {
+        assert_eq!(
+            highlight("Anna Annabel", "ann"),
+            "<mark>Ann</mark>a <mark>Ann</mark>abel"
+        );
+    }
+
+    #[test]
+    fn escapes_the_text_around_and_inside_matches() {
+        assert_eq!(
+            highlight("<b>Tom & Jerry</b>", "& j"),
+            "&lt;b&gt;Tom <mark>&amp; J</mark>erry&lt;&#x2f;b&gt;"
+        );
+        assert_eq!(highlight("<i>", ""), "&lt;i&gt;");
+    }
+
+    #[test]
+    fn matches_stay_on_character_boundaries() {
+        // "İ" lowercases to "i̇", a query of just "i" must not split it
+        assert_eq!(highlight("İstanbul", "i"), "İstanbul");
+        assert_eq!(highlight("İstanbul", "İst"), "<mark>İst</mark>anbul");
+        assert_eq!(highlight("Müller", "MÜ"), "<mark>Mü</mark>ller");
+    }
+}
@@ -0,0 +1,220 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Redirect, Response},
+};
+use tokio::sync::Mutex;
+
+use crate::{cookies, verification::generate_token, AppState};
+
+// Holds the session token of a logged in browser
+pub const SESSION_COOKIE: &str = "session";
+
+pub struct LoginConfig {
+    // Argon2 hash of the password sent in the login form, in PHC format. Given as
+    // LOGIN_PASSWORD_HASH, or LOGIN_PASSWORD is hashed at startup and not kept.
+    pub password_hash: String,
+    // A session without requests for this long is logged out (SESSION_TIMEOUT_SECS)
+    pub timeout: Duration,
+    // Every request restarts the timeout, otherwise it runs from the login
+    // (SESSION_SLIDING=0 turns it off)
+    pub sliding: bool,
+}
+
+// Logged in sessions by token, kept in memory so a restart logs everyone out
+pub struct Sessions {
+    timeout: Duration,
+    sliding: bool,
+    sessions: Mutex<HashMap<String, Instant>>,
+}
+
+impl Sessions {
+    pub fn new(config: &LoginConfig) -> Self {
+        Self {
+            timeout: config.timeout,
+            sliding: config.sliding,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Starts a session and returns its token
+    pub async fn start(&self) -> String {
+        let token = generate_token();
+        let expires_at = Instant::now() + self.timeout;
+        self.sessions.lock().await.insert(token.clone(), expires_at);
+        token
+    }
+
+    // Whether the session is still logged in, extending it when the timeout slides. An
+    // expired session is dropped.
+    pub async fn check(&self, token: &str) -> bool {
+        let mut sessions = self.sessions.lock().await;
+        let now = Instant::now();
+        match sessions.get_mut(token) {
+            Some(expires_at) if *expires_at > now => {
+                if self.sliding {
+                    *expires_at = now + self.timeout;
+                }
+                true
+            }
+            Some(_) => {
+                sessions.remove(token);
+                false
+            }
+            None => false,
+        }
+    }
+
+    pub async fn end(&self, token: &str) {
+        self.sessions.lock().await.remove(token);
+    }
+
+    // Drops sessions that timed out without another request. Returns how many were removed.
+    pub async fn purge_expired(&self) -> usize {
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().await;
+        let before = sessions.len();
+        sessions.retain(|_, expires_at| *expires_at > now);
+        before - sessions.len()
+    }
+}
+
+// Argon2id with a random salt per password, printed by `axum-app --hash-password`
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::encode_b64(&rand::random::<[u8; 16]>()).expect("16 bytes fit a salt");
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("Default Argon2 parameters are valid")
+        .to_string()
+}
+
+// Reads the password from the first line of stdin and prints the LOGIN_PASSWORD_HASH for it
+pub fn print_password_hash() {
+    let mut password = String::new();
+    if let Err(err) = std::io::stdin().read_line(&mut password) {
+        eprintln!("Failed to read the password: {err}");
+        std::process::exit(1);
+    }
+    println!("{}", hash_password(password.trim_end_matches(['\r', '\n'])));
+}
+
+pub fn is_password_hash(hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok()
+}
+
+// Takes the parameters and salt from the hash, the comparison runs in constant time.
+// Deliberately slow, so it's meant for a blocking thread.
+pub fn verify_password(given: &str, hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(given.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+// Sends requests without a live session to the login page, only when LOGIN_PASSWORD is set.
+// htmx requests get HX-Redirect since a redirect would be followed and swapped into the page.
+pub async fn require_login(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(sessions) = &state.sessions else {
+        return next.run(request).await;
+    };
+    let token = cookies::get(request.headers(), SESSION_COOKIE);
+    if let Some(token) = &token {
+        if sessions.check(token).await {
+            return next.run(request).await;
+        }
+    }
+    let mut response = if request.headers().contains_key("hx-request") {
+        (StatusCode::UNAUTHORIZED, [("HX-Redirect", "/login")]).into_response()
+    } else {
+        Redirect::to("/login").into_response()
+    };
+    // Forgets the expired token instead of sending it along with every request
+    if token.is_some() {
+        if let Ok(cookie) = HeaderValue::from_str(&cookies::clear(SESSION_COOKIE)) {
+            response.headers_mut().insert(header::SET_COOKIE, cookie);
+        }
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_millis(200);
+
+    fn sessions(sliding: bool) -> Sessions {
+        Sessions::new(&LoginConfig {
+            password_hash: String::new(),
+            timeout: TIMEOUT,
+            sliding,
+        })
+    }
+
+    #[tokio::test]
+    async fn idle_session_expires() {
+        let sessions = sessions(true);
+        let token = sessions.start().await;
+        assert!(sessions.check(&token).await);
+        tokio::time::sleep(TIMEOUT + Duration::from_millis(20)).await;
+        assert!(!sessions.check(&token).await);
+        // Dropped once found expired, so it doesn't come back
+        assert!(sessions.sessions.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn requests_extend_a_sliding_session() {
+        let sessions = sessions(true);
+        let token = sessions.start().await;
+        for _ in 0..3 {
+            tokio::time::sleep(TIMEOUT / 2).await;
+            assert!(sessions.check(&token).await);
+        }
+    }
+
+    #[tokio::test]
+    async fn fixed_session_expires_despite_requests() {
+        let sessions = sessions(false);
+        let token = sessions.start().await;
+        tokio::time::sleep(TIMEOUT / 2).await;
+        assert!(sessions.check(&token).await);
+        tokio::time::sleep(TIMEOUT / 2 + Duration::from_millis(20)).await;
+        assert!(!sessions.check(&token).await);
+    }
+
+    #[tokio::test]
+    async fn purge_drops_only_expired_sessions() {
+        let sessions = sessions(true);
+        let expired = sessions.start().await;
+        tokio::time::sleep(TIMEOUT + Duration::from_millis(20)).await;
+        let live = sessions.start().await;
+        assert_eq!(sessions.purge_expired().await, 1);
+        assert!(sessions.check(&live).await);
+        assert!(!sessions.check(&expired).await);
+    }
+
+    #[test]
+    fn password_round_trips_through_the_hash() {
+        let hash = hash_password("correct horse");
+        assert!(is_password_hash(&hash));
+        assert!(verify_password("correct horse", &hash));
+        assert!(!verify_password("wrong horse", &hash));
+        assert!(!is_password_hash("correct horse"));
+    }
+}
@@ -0,0 +1,31 @@
+use tokio::signal;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+// Cancels the token on Ctrl-C or SIGTERM so the server and background tasks can stop cleanly
+pub fn cancel_on_signal(token: CancellationToken) {
+    tokio::spawn(async move {
+        let ctrl_c = async {
+            signal::ctrl_c()
+                .await
+                .expect("Failed to install Ctrl-C handler");
+        };
+
+        #[cfg(unix)]
+        let terminate = async {
+            signal::unix::signal(signal::unix::SignalKind::terminate())
+                .expect("Failed to install SIGTERM handler")
+                .recv()
+                .await;
+        };
+        #[cfg(not(unix))]
+        let terminate = std::future::pending::<()>();
+
+        tokio::select! {
+            _ = ctrl_c => {},
+            _ = terminate => {},
+        }
+        info!("Shutting down");
+        token.cancel();
+    });
+}
@@ -0,0 +1,84 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::atomic::Ordering::SeqCst,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Contact, ContactsAppState, CounterAppState, DEFAULT_COUNTER, NEXT_ID};
+
+// Everything kept in memory, in a shape that can be exported and imported again
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Snapshot {
+    // The default counter, kept apart so snapshots from before named counters still load
+    pub count: usize,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub counters: BTreeMap<String, usize>,
+    pub contacts: Vec<Contact>,
+}
+
+impl Snapshot {
+    // Copies the current state, holding both locks so the snapshot is consistent
+    pub async fn capture(counter: &CounterAppState, contacts: &ContactsAppState) -> Self {
+        let counts = counter.counts.lock().await;
+        let contacts = contacts.contacts.lock().await;
+        Self {
+            count: counts.get(DEFAULT_COUNTER).copied().unwrap_or_default(),
+            counters: counts
+                .iter()
+                .filter(|(name, _)| *name != DEFAULT_COUNTER)
+                .map(|(name, count)| (name.clone(), *count))
+                .collect(),
+            contacts: contacts.clone(),
+        }
+    }
+
+    // Checks the invariants the handlers rely on, ids and emails have to be unique. A contact
+    // may have no email yet, like a duplicated one.
+    pub fn validate(&self) -> Result<(), String> {
+        let mut ids = HashSet::new();
+        let mut emails = HashSet::new();
+        for contact in &self.contacts {
+            if !ids.insert(contact.id) {
+                return Err(format!("Duplicate contact id {}", contact.id));
+            }
+            if !contact.email.is_empty() && !emails.insert(contact.email.as_str()) {
+                return Err(format!("Duplicate contact email {}", contact.email));
+            }
+        }
+        Ok(())
+    }
+
+    // All counters by name, the default one included
+    pub fn counts(&self) -> HashMap<String, usize> {
+        let mut counts: HashMap<_, _> = self.counters.clone().into_iter().collect();
+        counts.insert(DEFAULT_COUNTER.to_string(), self.count);
+        counts
+    }
+
+    // Makes sure newly created contacts get ids past the highest one in the snapshot
+    pub fn reserve_ids(&self) {
+        let max_id = self
+            .contacts
+            .iter()
+            .map(|contact| contact.id)
+            .max()
+            .unwrap_or(0);
+        NEXT_ID.fetch_max(max_id + 1, SeqCst);
+    }
+
+    // Replaces the current state, new contacts get ids past the highest imported one
+    pub async fn restore(self, counter: &CounterAppState, contacts: &ContactsAppState) {
+        let mut counts = counter.counts.lock().await;
+        let mut current_contacts = contacts.contacts.lock().await;
+        self.reserve_ids();
+        *counts = self.counts();
+        // Otherwise the next start would load the counter values from before the restore
+        if let Some(counter_file) = &counter.counter_file {
+            counter_file.save(&counts).await;
+        }
+        *current_contacts = self.contacts;
+        contacts.contacts_replaced(&current_contacts);
+    }
+}
@@ -0,0 +1,194 @@
+use std::{cmp::Ordering, collections::HashSet};
+
+use tracing::warn;
+
+use crate::Contact;
+
+#[derive(Clone, Copy)]
+enum SortKey {
+    Id,
+    Name,
+    Email,
+    Manual,
+    Favorite,
+}
+
+#[derive(Clone, Copy)]
+struct SortField {
+    key: SortKey,
+    descending: bool,
+}
+
+// Composite sort order parsed from a query like `favorite:desc,name:asc`
+pub struct ContactSorter {
+    fields: Vec<SortField>,
+}
+
+impl ContactSorter {
+    // Unknown keys or directions are skipped with a warning instead of failing the request
+    pub fn parse(raw: &str) -> Self {
+        let fields = raw
+            .split(',')
+            .map(str::trim)
+            .filter(|part| !part.is_empty())
+            .filter_map(|part| {
+                let (key, dir) = part.split_once(':').unwrap_or((part, "asc"));
+                let key = match key.trim() {
+                    "id" => SortKey::Id,
+                    "name" => SortKey::Name,
+                    "email" => SortKey::Email,
+                    "manual" => SortKey::Manual,
+                    "favorite" => SortKey::Favorite,
+                    unknown => {
+                        warn!("Ignoring unknown sort key {unknown:?}");
+                        return None;
+                    }
+                };
+                let descending = match dir.trim() {
+                    "asc" => false,
+                    "desc" => true,
+                    unknown => {
+                        warn!("Ignoring unknown sort direction {unknown:?}");
+                        return None;
+                    }
+                };
+                Some(SortField { key, descending })
+            })
+            .collect();
+        Self { fields }
+    }
+
+    // Stable sort, so contacts equal on every key keep their current order
+    pub fn sort(&self, contacts: &mut [&Contact]) {
+        if self.fields.is_empty() {
+            return;
+        }
+        contacts.sort_by(|a, b| self.compare(a, b));
+    }
+
+    fn compare(&self, a: &Contact, b: &Contact) -> Ordering {
+        self.fields
+            .iter()
+            .map(|field| {
+                let ordering = match field.key {
+                    SortKey::Id => a.id.cmp(&b.id),
+                    SortKey::Name => a.name.to_lowercase().cmp(&b.name.to_lowercase()),
+                    SortKey::Email => a.email.to_lowercase().cmp(&b.email.to_lowercase()),
+                    SortKey::Manual => a.order.cmp(&b.order),
+                    // Ascending puts favorites last, like false before true
+                    SortKey::Favorite => a.favorite.cmp(&b.favorite),
+                };
+                if field.descending {
+                    ordering.reverse()
+                } else {
+                    ordering
+                }
+            })
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+// Moves the given contacts into the given sequence. They take over the positions they held
+// between them, so reordering one page of the manual order leaves the other pages alone.
+pub fn reorder(contacts: &mut [Contact], ids: &[usize]) -> Result<(), String> {
+    let mut seen = HashSet::new();
+    if let Some(duplicate) = ids.iter().find(|id| !seen.insert(**id)) {
+        return Err(format!("Contact {duplicate} is listed twice"));
+    }
+    if let Some(unknown) = ids
+        .iter()
+        .find(|id| !contacts.iter().any(|contact| contact.id == **id))
+    {
+        return Err(format!("Contact {unknown} does not exist"));
+    }
+
+    // Renumber in the order the list shows, which also separates contacts sharing an order
+    let mut manual: Vec<usize> = (0..contacts.len()).rev().collect();
+    manual.sort_by_key(|&index| contacts[index].order);
+    for (order, &index) in manual.iter().enumerate() {
+        contacts[index].order = order as i64;
+    }
+
+    let mut slots: Vec<i64> = contacts
+        .iter()
+        .filter(|contact| seen.contains(&contact.id))
+        .map(|contact| contact.order)
+        .collect();
+    slots.sort_unstable();
+    for (id, slot) in ids.iter().zip(slots) {
+        if let Some(contact) = contacts.iter_mut().find(|contact| contact.id == *id) {
+            contact.order = slot;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn contact(id: usize, name: &str, favorite: bool) -> Contact {
+        serde_json::from_value(json!({
+            "id": id,
+            "name": name,
+            "email": format!("{}@example.com", name.to_lowercase()),
+            "favorite": favorite,
+        }))
+        .unwrap()
+    }
+
+    fn sorted_ids(sort: &str, contacts: &[Contact]) -> Vec<usize> {
+        let mut shown: Vec<&Contact> = contacts.iter().collect();
+        ContactSorter::parse(sort).sort(&mut shown);
+        shown.iter().map(|contact| contact.id).collect()
+    }
+
+    #[test]
+    fn favorites_first_then_name() {
+        let contacts = [
+            contact(1, "Carol", false),
+            contact(2, "bob", true),
+            contact(3, "Alice", false),
+            contact(4, "Dave", true),
+        ];
+        assert_eq!(
+            sorted_ids("favorite:desc,name:asc", &contacts),
+            [2, 4, 3, 1]
+        );
+    }
+
+    #[test]
+    fn direction_defaults_to_ascending() {
+        let contacts = [contact(1, "Bob", false), contact(2, "Alice", false)];
+        assert_eq!(sorted_ids(" name ", &contacts), [2, 1]);
+        assert_eq!(sorted_ids("name:desc", &contacts), [1, 2]);
+    }
+
+    #[test]
+    fn ties_keep_their_order() {
+        let contacts = [
+            contact(3, "Same", false),
+            contact(1, "Same", false),
+            contact(2, "Same", false),
+        ];
+        assert_eq!(sorted_ids("name", &contacts), [3, 1, 2]);
+        assert_eq!(sorted_ids("", &contacts), [3, 1, 2]);
+    }
+
+    #[test]
+    fn skips_unknown_keys_and_directions() {
+        let contacts = [
+            contact(1, "Bob", true),
+            contact(2, "Alice", false),
+            contact(3, "Carol", true),
+        ];
+        assert_eq!(sorted_ids("age:desc,name", &contacts), [2, 1, 3]);
+        assert_eq!(
+            sorted_ids("name:up,favorite:desc,name", &contacts),
+            [1, 3, 2]
+        );
+    }
+}
@@ -0,0 +1,159 @@
+use std::{
+    env,
+    sync::atomic::{AtomicU64, Ordering::Relaxed},
+};
+
+use axum::{
+    extract::MatchedPath,
+    http::{HeaderMap, Request, Response, StatusCode},
+};
+use opentelemetry::{global, propagation::Extractor, trace::TracerProvider};
+use opentelemetry_sdk::{propagation::TraceContextPropagator, trace::SdkTracerProvider, Resource};
+use tracing::{
+    field::{display, Empty},
+    info_span, Span,
+};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{filter::LevelFilter, fmt, layer::SubscriberExt, util::SubscriberInitExt};
+
+use crate::{client_ip::ClientIp, config::LogConfig, request_id::X_REQUEST_ID};
+
+const SERVICE_NAME: &str = "axum-app";
+
+// Sets up logging and, when OTEL_EXPORTER_OTLP_ENDPOINT is set, OTLP trace export.
+// The returned provider has to be shut down on exit so buffered spans get flushed.
+pub fn init_tracing(log_config: &LogConfig) -> Option<SdkTracerProvider> {
+    let tracer_provider = env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT").map(|_| {
+        // The exporter reads the endpoint and the other OTEL_* variables itself
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .build()
+            .expect("Failed to build the OTLP exporter");
+        SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(Resource::builder().with_service_name(SERVICE_NAME).build())
+            .build()
+    });
+    if tracer_provider.is_some() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+    }
+    let otel_layer = tracer_provider
+        .as_ref()
+        .map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer(SERVICE_NAME)));
+
+    tracing_subscriber::registry()
+        .with(LevelFilter::from_level(log_config.level))
+        .with(log_config.json.then(|| fmt::layer().json()))
+        .with((!log_config.json).then(fmt::layer))
+        .with(otel_layer)
+        .init();
+    tracer_provider
+}
+
+// Span for every request, joins the caller's trace when it sent a traceparent header
+pub fn make_request_span<B>(request: &Request<B>) -> Span {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(MatchedPath::as_str);
+    // Set by request_id::assign, which runs first
+    let request_id = request
+        .headers()
+        .get(X_REQUEST_ID)
+        .and_then(|value| value.to_str().ok());
+    let span = info_span!(
+        "request",
+        request_id,
+        method = %request.method(),
+        uri = %request.uri(),
+        version = ?request.version(),
+        route,
+        status = Empty,
+        client_ip = Empty,
+    );
+    // Only requests that change something get the address, page views stay anonymous
+    if !request.method().is_safe() {
+        if let Some(client_ip) = request.extensions().get::<ClientIp>() {
+            span.record("client_ip", display(client_ip));
+        }
+    }
+    let parent_cx = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(request.headers()))
+    });
+    // Fails only when no OpenTelemetry layer is installed, then there's nothing to join
+    let _ = span.set_parent(parent_cx);
+    span
+}
+
+pub fn record_status<B>(response: &Response<B>, span: &Span) {
+    span.record("status", response.status().as_u16());
+}
+
+// Decides which responses the trace layer logs: one in `every` successful ones, but every
+// client and server error so they stay visible however busy the server is
+pub struct ResponseSampler {
+    every: u64,
+    seen: AtomicU64,
+}
+
+impl ResponseSampler {
+    pub fn new(every: u64) -> Self {
+        Self {
+            every: every.max(1),
+            seen: AtomicU64::new(0),
+        }
+    }
+
+    pub fn should_log(&self, status: StatusCode) -> bool {
+        if status.is_client_error() || status.is_server_error() {
+            return true;
+        }
+        self.seen.fetch_add(1, Relaxed).is_multiple_of(self.every)
+    }
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_are_never_sampled_out() {
+        let sampler = ResponseSampler::new(1000);
+        // Uses up the one logged success of the first thousand
+        assert!(sampler.should_log(StatusCode::OK));
+        for status in [
+            StatusCode::BAD_REQUEST,
+            StatusCode::NOT_FOUND,
+            StatusCode::UNPROCESSABLE_ENTITY,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            StatusCode::SERVICE_UNAVAILABLE,
+        ] {
+            assert!((0..100).all(|_| sampler.should_log(status)), "{status}");
+        }
+    }
+
+    #[test]
+    fn logs_one_in_every_success() {
+        let sampler = ResponseSampler::new(3);
+        let logged: Vec<_> = (0..7).map(|_| sampler.should_log(StatusCode::OK)).collect();
+        assert_eq!(logged, [true, false, false, true, false, false, true]);
+        // Errors don't count towards the successes
+        assert!(sampler.should_log(StatusCode::NOT_FOUND));
+        assert!(!sampler.should_log(StatusCode::NOT_MODIFIED));
+
+        let unsampled = ResponseSampler::new(0);
+        assert!((0..10).all(|_| unsampled.should_log(StatusCode::OK)));
+    }
+}
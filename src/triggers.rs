@@ -0,0 +1,129 @@
+use std::convert::Infallible;
+
+use axum::{
+    http::{HeaderMap, HeaderName, HeaderValue},
+    response::{IntoResponse, IntoResponseParts, Response, ResponseParts},
+};
+use serde_json::{Map, Value};
+
+use crate::request_id;
+
+const HX_TRIGGER: HeaderName = HeaderName::from_static("hx-trigger");
+const HX_TRIGGER_AFTER_SWAP: HeaderName = HeaderName::from_static("hx-trigger-after-swap");
+const HX_TRIGGER_AFTER_SETTLE: HeaderName = HeaderName::from_static("hx-trigger-after-settle");
+
+// Events for htmx to fire on the client, sent as the HX-Trigger headers. Each header
+// carries a JSON object of event names and their details, so events added at the same
+// point end up in one header and ones already on the response are kept. Details also carry
+// the id of the request under `requestId`, see request_id.rs.
+#[derive(Default)]
+pub struct Triggers {
+    received: Map<String, Value>,
+    after_swap: Map<String, Value>,
+    after_settle: Map<String, Value>,
+}
+
+impl Triggers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Fired as soon as the response arrives, before anything is swapped
+    pub fn trigger(mut self, event: &str, detail: Value) -> Self {
+        self.received.insert(event.to_string(), detail);
+        self
+    }
+
+    // Fired once the response is swapped into the page
+    pub fn after_swap(mut self, event: &str, detail: Value) -> Self {
+        self.after_swap.insert(event.to_string(), detail);
+        self
+    }
+
+    // Fired once the swapped content has settled, e.g. for focusing an input in it
+    pub fn after_settle(mut self, event: &str, detail: Value) -> Self {
+        self.after_settle.insert(event.to_string(), detail);
+        self
+    }
+}
+
+impl IntoResponseParts for Triggers {
+    type Error = Infallible;
+
+    fn into_response_parts(self, mut res: ResponseParts) -> Result<ResponseParts, Self::Error> {
+        let request_id = request_id::current();
+        for (name, mut events) in [
+            (HX_TRIGGER, self.received),
+            (HX_TRIGGER_AFTER_SWAP, self.after_swap),
+            (HX_TRIGGER_AFTER_SETTLE, self.after_settle),
+        ] {
+            if let Some(request_id) = &request_id {
+                add_request_id(&mut events, request_id);
+            }
+            merge(res.headers_mut(), name, events);
+        }
+        Ok(res)
+    }
+}
+
+// For responses that only fire events, like a 204 with nothing to swap
+impl IntoResponse for Triggers {
+    fn into_response(self) -> Response {
+        (self, ()).into_response()
+    }
+}
+
+// Object details get the id as another key, events without details get it as theirs. Other
+// details are left as they are, a listener reading a string detail would miss it otherwise.
+fn add_request_id(events: &mut Map<String, Value>, request_id: &str) {
+    for detail in events.values_mut() {
+        match detail {
+            Value::Object(detail) => {
+                detail.insert("requestId".to_string(), request_id.into());
+            }
+            Value::Null => {
+                *detail = Value::Object(Map::from_iter([(
+                    "requestId".to_string(),
+                    request_id.into(),
+                )]));
+            }
+            _ => {}
+        }
+    }
+}
+
+// Adds the events to those already in the header. A plain list of event names like
+// `a, b` is read as those events without details.
+fn merge(headers: &mut HeaderMap, name: HeaderName, mut events: Map<String, Value>) {
+    if events.is_empty() {
+        return;
+    }
+    if let Some(existing) = headers.get(&name).and_then(|value| value.to_str().ok()) {
+        let mut combined = match serde_json::from_str(existing) {
+            Ok(Value::Object(existing)) => existing,
+            _ => existing
+                .split(',')
+                .map(str::trim)
+                .filter(|event| !event.is_empty())
+                .map(|event| (event.to_string(), Value::Null))
+                .collect(),
+        };
+        combined.append(&mut events);
+        events = combined;
+    }
+    headers.insert(name, header_value(&Value::Object(events)));
+}
+
+// Header values have to be ASCII, JSON escapes keep other characters intact
+fn header_value(payload: &Value) -> HeaderValue {
+    let escaped: String = payload
+        .to_string()
+        .encode_utf16()
+        .map(|unit| match char::from_u32(unit.into()) {
+            Some(c) if c.is_ascii() => c.to_string(),
+            _ => format!("\\u{unit:04x}"),
+        })
+        .collect();
+    // serde_json escapes control characters, so only visible ASCII and spaces are left
+    HeaderValue::try_from(escaped).expect("Escaped JSON is a valid header value")
+}
@@ -0,0 +1,44 @@
+use qrcode::{render::svg, QrCode};
+
+use crate::{
+    birthday::{escape_text, push_line, Birthday},
+    Contact,
+};
+
+// A vCard 3.0 for importing the contact into an address book, the version phones read best.
+// Notes can be left out, they're most of the size and a QR code only holds so much.
+pub fn card(contact: &Contact, with_notes: bool) -> String {
+    let mut card = String::new();
+    push_line(&mut card, "BEGIN:VCARD");
+    push_line(&mut card, "VERSION:3.0");
+    let name = escape_text(&contact.name);
+    push_line(&mut card, &format!("FN:{name}"));
+    // Required in 3.0, the name isn't split into its parts
+    push_line(&mut card, &format!("N:{name};;;;"));
+    push_line(
+        &mut card,
+        &format!("EMAIL;TYPE=INTERNET:{}", escape_text(&contact.email)),
+    );
+    if !contact.phone.is_empty() {
+        push_line(&mut card, &format!("TEL:{}", escape_text(&contact.phone)));
+    }
+    if let Some(birthday) = Birthday::parse(&contact.birthday) {
+        push_line(&mut card, &format!("BDAY:{birthday}"));
+    }
+    if !contact.tags.is_empty() {
+        let tags: Vec<String> = contact.tags.iter().map(|tag| escape_text(tag)).collect();
+        push_line(&mut card, &format!("CATEGORIES:{}", tags.join(",")));
+    }
+    if with_notes && !contact.notes.is_empty() {
+        push_line(&mut card, &format!("NOTE:{}", escape_text(&contact.notes)));
+    }
+    push_line(&mut card, "END:VCARD");
+    card
+}
+
+// The contact's vCard as a QR code for scanning with a phone, without the notes. Fails only
+// when the card is too long for the largest QR code.
+pub fn qr_svg(contact: &Contact) -> Result<String, qrcode::types::QrError> {
+    let code = QrCode::new(card(contact, false))?;
+    Ok(code.render::<svg::Color>().min_dimensions(256, 256).build())
+}
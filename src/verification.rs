@@ -0,0 +1,68 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+use tokio::sync::Mutex;
+
+// One-time tokens that confirm a new contact's email address
+pub struct VerificationTokens {
+    ttl: Duration,
+    tokens: Mutex<HashMap<String, PendingVerification>>,
+}
+
+struct PendingVerification {
+    contact_id: usize,
+    expires_at: Instant,
+}
+
+pub enum TokenCheck {
+    Valid(usize),
+    Expired,
+    Unknown,
+}
+
+impl VerificationTokens {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            tokens: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn issue(&self, contact_id: usize) -> String {
+        let token = generate_token();
+        let pending = PendingVerification {
+            contact_id,
+            expires_at: Instant::now() + self.ttl,
+        };
+        self.tokens.lock().await.insert(token.clone(), pending);
+        token
+    }
+
+    // A valid token is used up, an expired one stays so the link keeps reporting as expired
+    pub async fn redeem(&self, token: &str) -> TokenCheck {
+        let mut tokens = self.tokens.lock().await;
+        match tokens.get(token) {
+            None => TokenCheck::Unknown,
+            Some(pending) if pending.expires_at <= Instant::now() => TokenCheck::Expired,
+            Some(_) => TokenCheck::Valid(tokens.remove(token).unwrap().contact_id),
+        }
+    }
+
+    // Drops tokens that expired more than one ttl ago, until then their links report as
+    // expired instead of unknown. Returns the number of removed tokens.
+    pub async fn purge_expired(&self) -> usize {
+        let cutoff = Instant::now().checked_sub(self.ttl);
+        let mut tokens = self.tokens.lock().await;
+        let before = tokens.len();
+        tokens.retain(|_, pending| cutoff.is_none_or(|cutoff| pending.expires_at > cutoff));
+        before - tokens.len()
+    }
+}
+
+pub fn generate_token() -> String {
+    let bytes: [u8; 16] = rand::rng().random();
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}